@@ -1,9 +1,14 @@
 use pkg_config;
+use std::env;
 
 fn main() {
+    // only the speexdsp feature links the system library
+    if env::var_os("CARGO_FEATURE_SPEEXDSP").is_none() {
+        return;
+    }
     // Use pkg-config to find and link speexdsp
     pkg_config::Config::new()
         .atleast_version("1.2.1")
         .probe("speexdsp")
         .expect("Could not find speexdsp using pkg-config");
-}
\ No newline at end of file
+}
@@ -0,0 +1,187 @@
+use anyhow::{anyhow, Result};
+use std::{f64::consts::PI, thread};
+use tokio::sync::mpsc;
+use crate::audio::io::AudioStream;
+use crate::audio::track::AudioTrack;
+use crate::signal::rate::{beat_period, SECONDS_PER_DAY};
+
+// length of a single click burst in time constants
+const BURST_LENGTH: f64 = 8.0;
+
+#[derive(Debug, Clone)]
+pub struct WatchParams {
+    pub bph: u32,
+    // rate in s/d, positive for a gaining watch
+    pub rate: f64,
+    // beat error in ms
+    pub beat_error: f64,
+    // balance amplitude and lift angle in degrees
+    pub amplitude: f64,
+    pub lift_angle: f64,
+    // standard deviation of the added white noise
+    pub noise: f64,
+    pub samplerate: f64,
+    // peak level of the loudest (drop) click
+    pub level: f64,
+    // ringing frequency and decay time of a click in Hz and s
+    pub click_freq: f64,
+    pub click_decay: f64,
+    pub seed: u64,
+}
+
+impl Default for WatchParams {
+    fn default() -> Self {
+        Self {
+            bph: 28800,
+            rate: 0.0,
+            beat_error: 0.0,
+            amplitude: 280.0,
+            lift_angle: 52.0,
+            noise: 0.001,
+            samplerate: 44100.0,
+            level: 0.1,
+            click_freq: 5000.0,
+            click_decay: 0.0003,
+            seed: 1,
+        }
+    }
+}
+
+// simulated escapement, every beat is an unlock, impulse and drop click
+pub struct SignalGenerator {
+    params: WatchParams,
+    rng: u64,
+    position: usize,
+}
+
+impl SignalGenerator {
+    pub fn new(params: WatchParams) -> Self {
+        Self {
+            rng: params.seed.max(1),
+            params,
+            position: 0,
+        }
+    }
+
+    // time between unlock and drop that gives the requested amplitude
+    pub fn lift_time(&self) -> f64 {
+        let p = &self.params;
+        let ratio = (p.lift_angle / (2.0 * p.amplitude)).clamp(0.0, 1.0);
+        ratio.asin() * 7200.0 / (PI * p.bph as f64)
+    }
+
+    // actual time between two beats including the rate error
+    pub fn period(&self) -> f64 {
+        beat_period(self.params.bph) / (1.0 + self.params.rate / SECONDS_PER_DAY)
+    }
+
+    // onset of beat k, tocks are shifted by the beat error
+    fn beat_time(&self, k: i64) -> f64 {
+        let offset = if k % 2 == 0 { 0.0 } else { 0.001 * self.params.beat_error };
+        self.period() * (k as f64 + 0.5) + offset
+    }
+
+    // xorshift64 with a Box-Muller transform, keeps the generator free of extra deps
+    fn next_uniform(&mut self) -> f64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        ((self.rng >> 11) as f64 + 0.5) / (1u64 << 53) as f64
+    }
+
+    fn next_gaussian(&mut self) -> f64 {
+        let (u1, u2) = (self.next_uniform(), self.next_uniform());
+        (-2.0 * u1.ln()).sqrt() * (2.0 * PI * u2).cos()
+    }
+
+    // add a decaying sine burst starting at `time` to the block starting at sample `start`
+    fn add_click(&self, block: &mut [f64], start: usize, time: f64, level: f64) {
+        let p = &self.params;
+        let first = (time * p.samplerate).ceil().max(0.0) as usize;
+        let last = ((time + BURST_LENGTH * p.click_decay) * p.samplerate).ceil() as usize;
+
+        for n in first.max(start)..last.min(start + block.len()) {
+            let t = n as f64 / p.samplerate - time;
+            block[n - start] += level * (-t / p.click_decay).exp() * (2.0 * PI * p.click_freq * t).sin();
+        }
+    }
+
+    // next `len` samples of the signal, consecutive calls continue the same signal
+    pub fn next_block(&mut self, len: usize) -> Vec<f64> {
+        let start = self.position;
+        let samplerate = self.params.samplerate;
+        let mut block = vec![0.0; len];
+
+        let lift = self.lift_time();
+        let period = self.period();
+        let block_start = start as f64 / samplerate;
+        let block_end = (start + len) as f64 / samplerate;
+
+        // every beat whose clicks reach into the block
+        let reach = lift + BURST_LENGTH * self.params.click_decay + 0.001 * self.params.beat_error.abs();
+        let first_beat = ((block_start - reach) / period).floor() as i64 - 1;
+        let last_beat = (block_end / period).ceil() as i64;
+
+        for k in first_beat.max(0)..=last_beat {
+            let onset = self.beat_time(k);
+            let level = self.params.level;
+            self.add_click(&mut block, start, onset, 0.5 * level);
+            self.add_click(&mut block, start, onset + 0.4 * lift, 0.7 * level);
+            self.add_click(&mut block, start, onset + lift, level);
+        }
+
+        if self.params.noise > 0.0 {
+            for value in block.iter_mut() {
+                *value += self.params.noise * self.next_gaussian();
+            }
+        }
+
+        self.position += len;
+        block
+    }
+
+    pub fn generate(&mut self, duration: f64) -> AudioTrack {
+        let samplerate = self.params.samplerate;
+        let start = self.position;
+        let len = (duration * samplerate).round() as usize;
+
+        let volume = self.next_block(len);
+        let track = volume
+            .iter()
+            .enumerate()
+            .map(|(n, &v)| ((start + n + 1) as f64 / samplerate, v))
+            .collect::<Vec<(f64, f64)>>();
+        AudioTrack::from_rate_track(samplerate, track)
+    }
+
+    // stream the signal like a live device, endless when no duration is given
+    pub fn build(self, duration: Option<f64>) -> Result<AudioStream> {
+        let (sender, receiver) = mpsc::channel(10000);
+        let samplerate = self.params.samplerate;
+        let mut generator = self;
+
+        thread::Builder::new()
+            .name("signal-generator".to_string())
+            .spawn(move || {
+                let block = (0.1 * samplerate).round().max(1.0) as usize;
+                let total = duration.map(|d| (d * samplerate).round() as usize);
+
+                loop {
+                    let len = match total {
+                        Some(total) if generator.position >= total => break,
+                        Some(total) => block.min(total - generator.position),
+                        None => block,
+                    };
+                    let track = generator.generate(len as f64 / samplerate);
+                    for sample in track.track {
+                        if sender.blocking_send(sample).is_err() {
+                            return;
+                        }
+                    }
+                }
+            })
+            .map_err(|e| anyhow!("Unable to start signal generator due to {:}", e))?;
+
+        Ok(AudioStream::from_receiver(samplerate, receiver))
+    }
+}
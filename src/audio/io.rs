@@ -2,21 +2,31 @@ use anyhow::{anyhow, Context, Result};
 use core::fmt;
 use cpal::{
     traits::{DeviceTrait, HostTrait, StreamTrait},
+    BufferSize,
     Device, 
     Host,
     HostId, 
+    SampleFormat,
+    SampleRate,
     Stream, 
+    StreamConfig,
+    SupportedBufferSize,
     SupportedStreamConfig};
+use ringbuf::{traits::{Consumer, Observer, Producer, Split}, HeapCons, HeapProd, HeapRb};
 use std::{
-    sync::Arc,
-    collections::HashMap
+    sync::{atomic::{AtomicU64, Ordering}, mpsc as std_mpsc, Arc, Weak},
+    collections::HashMap,
+    thread,
+    time::Duration,
 };
 use tokio::{
     spawn,
     sync::{mpsc, Mutex},
 };
 use futures::stream::{self, Stream as FuturStream, StreamExt};
+use serde::{Deserialize, Serialize};
 use std::pin::Pin;
+use std::str::FromStr;
 use crate::audio::track::AudioTrack;
 
 pub fn get_connectors() -> Result<Vec<Connector>> {
@@ -34,6 +44,35 @@ pub fn get_connectors() -> Result<Vec<Connector>> {
     connectors
 }
 
+// sample formats the capture callback knows how to convert
+pub const SAMPLE_FORMATS: [SampleFormat; 4] = [SampleFormat::I8, SampleFormat::I16, SampleFormat::I32, SampleFormat::F32];
+
+// rates offered besides the limits of each supported range
+pub const COMMON_SAMPLERATES: [u32; 6] = [44100, 48000, 88200, 96000, 176400, 192000];
+// highest rate the analysis keeps up with, it falls behind the stream above it
+pub const MAX_SAMPLERATE: u32 = 192000;
+
+pub fn sample_format_from_str(name: &str) -> Option<SampleFormat> {
+    SAMPLE_FORMATS.iter().find(|f| f.to_string().eq_ignore_ascii_case(name)).copied()
+}
+
+// one range of input configurations supported by a device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InputConfig {
+    pub channels: u16,
+    pub sample_format: SampleFormat,
+    pub min_samplerate: u32,
+    pub max_samplerate: u32,
+    // min and max buffer size in frames, None when the host does not report it
+    pub buffer_size: Option<(u32, u32)>,
+}
+
+impl InputConfig {
+    pub fn supports(&self, samplerate: u32) -> bool {
+        self.min_samplerate <= samplerate && samplerate <= self.max_samplerate
+    }
+}
+
 pub struct Connector {
     host: Host,
     devices: HashMap<String, Device>,
@@ -54,6 +93,10 @@ impl Connector {
         })
     }
 
+    pub fn name(&self) -> String {
+        self.host.id().name().to_string()
+    }
+
     pub fn list_device_names(&self) -> Option<Vec<String>>{
         match self.devices.len(){
             0 => None,
@@ -61,6 +104,31 @@ impl Connector {
         }
     }
 
+    // supported input configurations of a device, limited to the formats we can capture
+    pub fn list_input_configs(&self, name: &String) -> Result<Vec<InputConfig>> {
+        let dev = self
+            .devices
+            .get(name)
+            .ok_or(anyhow!("Unable to find device: {}", name))?;
+        let configs = dev
+            .supported_input_configs()
+            .map_err(|e| anyhow!("Unable to obtain configs for {:} due to {:}", name, e))?;
+
+        Ok(configs
+            .filter(|conf| SAMPLE_FORMATS.contains(&conf.sample_format()))
+            .map(|conf| InputConfig {
+                channels: conf.channels(),
+                sample_format: conf.sample_format(),
+                min_samplerate: conf.min_sample_rate().0,
+                max_samplerate: conf.max_sample_rate().0,
+                buffer_size: match conf.buffer_size() {
+                    SupportedBufferSize::Range { min, max } => Some((*min, *max)),
+                    SupportedBufferSize::Unknown => None,
+                },
+            })
+            .collect())
+    }
+
     fn get_stream_conf(&self, name: &String) -> Result<(Device, SupportedStreamConfig)> {
         // let dev: Option<Device> = self.devices.remove(name);
         let dev: Option<Device> = self.devices.get(name).cloned();
@@ -99,60 +167,238 @@ impl fmt::Debug for Connector {
 }
 
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Channel {
+    Left,
+    Right,
+    Mix,
+}
+
+impl Channel {
+    pub const ALL: [Channel; 3] = [Channel::Left, Channel::Right, Channel::Mix];
+
+    // value of the channel in one interleaved frame, mono frames give their only sample
+    pub fn value(&self, frame: &[f64]) -> f64 {
+        match self {
+            Channel::Left => frame[0],
+            Channel::Right => frame[1.min(frame.len() - 1)],
+            Channel::Mix => frame.iter().sum::<f64>() / frame.len() as f64,
+        }
+    }
+}
+
+impl fmt::Display for Channel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Channel::Left => write!(f, "Left"),
+            Channel::Right => write!(f, "Right"),
+            Channel::Mix => write!(f, "Mix"),
+        }
+    }
+}
+
+impl FromStr for Channel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "left" => Ok(Channel::Left),
+            "right" => Ok(Channel::Right),
+            "mix" => Ok(Channel::Mix),
+            _ => Err(anyhow!("Unknown channel '{:}', use left, right or mix", s)),
+        }
+    }
+}
+
+// which part of an interleaved frame goes to one output stream
+#[derive(Debug, Clone, Copy)]
+enum Pick {
+    Select(Channel),
+    Index(usize),
+}
+
+impl Pick {
+    fn value(&self, frame: &[f64]) -> f64 {
+        match self {
+            Pick::Select(channel) => channel.value(frame),
+            Pick::Index(ind) => frame[(*ind).min(frame.len() - 1)],
+        }
+    }
+}
+
+// how samples travel from the audio callback to the AudioStream
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Transport {
+    // bounded tokio channel
+    Channel,
+    // lock-free single producer single consumer ring buffer
+    RingBuffer,
+}
+
+enum SampleSink {
+    Channel(mpsc::Sender<(f64, f64)>),
+    Ring(HeapProd<(f64, f64)>),
+}
+
+impl SampleSink {
+    // false only when the consumer is alive but can not keep up
+    fn push(&mut self, sample: (f64, f64)) -> bool {
+        match self {
+            SampleSink::Channel(sender) => sender.is_closed() || sender.try_send(sample).is_ok(),
+            SampleSink::Ring(prod) => !prod.read_is_held() || prod.try_push(sample).is_ok(),
+        }
+    }
+}
+
+// lets the capture thread see whether anyone still listens to an output
+enum SinkWatch {
+    Channel(mpsc::Sender<(f64, f64)>),
+    Ring(Weak<()>),
+}
+
+impl SinkWatch {
+    fn is_open(&self) -> bool {
+        match self {
+            SinkWatch::Channel(sender) => !sender.is_closed(),
+            SinkWatch::Ring(token) => token.strong_count() > 0,
+        }
+    }
+}
+
+struct ChannelOutput {
+    sink: SampleSink,
+    pick: Pick,
+    dropped: Arc<AtomicU64>,
+}
 
 pub struct AudioStreamBuilder {
     samplerate: f64,
-    samplebuff: mpsc::Receiver<(f64,f64)>,
-    stream: Stream,
+    device: Device,
+    conf: SupportedStreamConfig,
+    // fixed buffer size in frames, host default when None
+    buffer_size: Option<u32>,
+    channel: Channel,
+    transport: Transport,
 }
 
 impl AudioStreamBuilder {
     pub fn new(con: &Connector, dev: &String) -> Result<Self> {
         // here we start creation of the new stream from the connector with a device named ...
-        let (dev, conf) = con.get_stream_conf(dev)?;
+        let (device, conf) = con.get_stream_conf(dev)?;
         let samplerate: f64 = conf.sample_rate().0 as f64;
 
-        // // create buffer which will store the data and clone to give to the sampling function
-        // let buffer: Arc<Mutex<Vec<(f64, f64)>>> = Arc::new(Mutex::new(Vec::new()));
-        // let clone_buff: Arc<Mutex<Vec<(f64, f64)>>> = Arc::clone(&buffer);
-        
-        let (sender, receiver) = mpsc::channel(10000);
+        // finally output the AudioStreamBuilder
+        Ok(Self {
+            samplerate,
+            device,
+            conf,
+            buffer_size: None,
+            channel: Channel::Mix,
+            transport: Transport::Channel,
+        })
+    }
+
+    // pick a supported config with the given sample rate and format, None keeps the
+    // device default, the default channel count is preferred when there is a choice
+    pub fn with_format(mut self, samplerate: Option<u32>, sample_format: Option<SampleFormat>) -> Result<Self> {
+        let rate = samplerate.unwrap_or(self.conf.sample_rate().0);
+        let format = sample_format.unwrap_or(self.conf.sample_format());
+        if (rate, format) == (self.conf.sample_rate().0, self.conf.sample_format()) {
+            return Ok(self);
+        }
+        if rate > MAX_SAMPLERATE {
+            return Err(anyhow!("Sample rates above {:} Hz are not supported", MAX_SAMPLERATE));
+        }
+
+        let mut ranges = self
+            .device
+            .supported_input_configs()
+            .map_err(|e| anyhow!("Unable to obtain configs due to {:}", e))?
+            .filter(|conf| {
+                conf.sample_format() == format
+                    && conf.min_sample_rate().0 <= rate
+                    && rate <= conf.max_sample_rate().0
+            })
+            .collect::<Vec<_>>();
+        ranges.sort_by_key(|conf| conf.channels() != self.conf.channels());
+
+        let range = ranges
+            .into_iter()
+            .next()
+            .ok_or(anyhow!("Device does not support {:} Hz with {:} samples", rate, format))?;
+        self.conf = range.with_sample_rate(SampleRate(rate));
+        self.samplerate = rate as f64;
+        Ok(self)
+    }
+
+    pub fn with_buffer_size(mut self, buffer_size: Option<u32>) -> Self {
+        self.buffer_size = buffer_size;
+        self
+    }
+
+    pub fn with_transport(mut self, transport: Transport) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    // channel used by build, multi-channel devices are mixed down by default
+    pub fn with_channel(mut self, channel: Channel) -> Self {
+        self.channel = channel;
+        self
+    }
+
+    pub fn channels(&self) -> u16 {
+        self.conf.channels()
+    }
+
+    fn build_stream(
+        dev: &Device,
+        conf: SupportedStreamConfig,
+        buffer_size: Option<u32>,
+        mut outputs: Vec<ChannelOutput>,
+    ) -> Result<Stream> {
+        let samplerate: f64 = conf.sample_rate().0 as f64;
+        let channels: usize = conf.channels().max(1) as usize;
+        let sample_format = conf.sample_format();
+        let mut config: StreamConfig = conf.into();
+        if let Some(frames) = buffer_size {
+            config.buffer_size = BufferSize::Fixed(frames);
+        }
 
         // define error callback for the stream
         let err_fn = |err| eprintln!("an error occurred on stream: {}", err);
 
         let mut last_time: f64 = 0.0;  // Track the time globally
         // start streating stream based off the sample format
-        let stream = match conf.sample_format() {
+        let stream = match sample_format {
             cpal::SampleFormat::I8 => dev.build_input_stream(
-                &conf.into(),
+                &config,
                 move |data, _: &_| {
-                    // AudioStreamBuilder::sample_collector::<i8>(data, &clone_buff, samplerate)
-                    AudioStreamBuilder::sample_collector::<i8>(data, sender.clone(), samplerate, &mut last_time)
+                    AudioStreamBuilder::sample_collector::<i8>(data, &mut outputs, channels, samplerate, &mut last_time)
                 },
                 err_fn,
                 None,
             )?,
             cpal::SampleFormat::I16 => dev.build_input_stream(
-                &conf.into(),
+                &config,
                 move |data, _: &_| {
-                    AudioStreamBuilder::sample_collector::<i16>(data, sender.clone(), samplerate, &mut last_time)
+                    AudioStreamBuilder::sample_collector::<i16>(data, &mut outputs, channels, samplerate, &mut last_time)
                 },
                 err_fn,
                 None,
             )?,
             cpal::SampleFormat::I32 => dev.build_input_stream(
-                &conf.into(),
+                &config,
                 move |data, _: &_| {
-                    AudioStreamBuilder::sample_collector::<i32>(data, sender.clone(), samplerate, &mut last_time)
+                    AudioStreamBuilder::sample_collector::<i32>(data, &mut outputs, channels, samplerate, &mut last_time)
                 },
                 err_fn,
                 None,
             )?,
             cpal::SampleFormat::F32 => dev.build_input_stream(
-                &conf.into(),
+                &config,
                 move |data, _: &_| {
-                    AudioStreamBuilder::sample_collector::<f32>(data, sender.clone(), samplerate, &mut last_time)
+                    AudioStreamBuilder::sample_collector::<f32>(data, &mut outputs, channels, samplerate, &mut last_time)
                 },
                 err_fn,
                 None,
@@ -163,48 +409,101 @@ impl AudioStreamBuilder {
                 )))
             }
         };
-
-        // finally output the AudioStreamBuilder
-        Ok(Self {
-            samplerate: samplerate,
-            samplebuff: receiver,
-            stream: stream,
-        })
+        Ok(stream)
     }
 
-    // this is the sampling function
-    fn sample_collector<T>(data: &[T], sender: mpsc::Sender<(f64, f64)>, samplerate: f64, last_time: &mut f64)
+    // this is the sampling function, data is interleaved so time advances once per frame
+    // and also for dropped samples, which leaves a visible gap in the time axis
+    fn sample_collector<T>(data: &[T], outputs: &mut [ChannelOutput], channels: usize, samplerate: f64, last_time: &mut f64)
     where
         T: cpal::Sample + Into<f64>,
     {
-        for sample in data.iter() {
+        let mut frame: Vec<f64> = Vec::with_capacity(channels);
+        for samples in data.chunks_exact(channels) {
             *last_time += 1.0 / samplerate;
-            let value = (*sample).into() as f64;
+            frame.clear();
+            frame.extend(samples.iter().map(|&s| s.into()));
 
-            if !sender.is_closed(){
-                // Attempt to send the data if chanel is closed break out 
-                let _ = sender.try_send((*last_time, value));
-            } else { break; }
+            for output in outputs.iter_mut() {
+                if !output.sink.push((*last_time, output.pick.value(&frame))) {
+                    output.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+            }
         }
     }
 
     pub fn build(self) -> Result<AudioStream>{
+        let pick = Pick::Select(self.channel);
+        let mut streams = self.start(vec![pick])?;
+        Ok(streams.remove(0))
+    }
+
+    // one stream per device channel, all sharing the same time axis
+    pub fn build_channels(self) -> Result<Vec<AudioStream>> {
+        let picks = (0..self.channels() as usize).map(Pick::Index).collect();
+        self.start(picks)
+    }
+
+    fn start(self, picks: Vec<Pick>) -> Result<Vec<AudioStream>> {
         // this function starts "listening" to the input and created data stream 
         // the last value is kept so to ensure the continuity in the saple timestamps
-        self.stream.play()?;
-        let receiver = self.samplebuff;
-        
-        let outputstream = stream::unfold(receiver, |mut receiver| async move {
-            match receiver.recv().await {
-                Some(sample) => Some((sample, receiver)),
-                None => None,
-            }
-        });
+        let samplerate = self.samplerate;
+        let mut outputs: Vec<ChannelOutput> = Vec::with_capacity(picks.len());
+        let mut watches: Vec<SinkWatch> = Vec::with_capacity(picks.len());
+        let mut streams: Vec<AudioStream> = Vec::with_capacity(picks.len());
+        for pick in picks {
+            let dropped = Arc::new(AtomicU64::new(0));
+            let (sink, mut audiostream) = match self.transport {
+                Transport::Channel => {
+                    // one second of samples like the ring buffer, the callback drops what does not fit
+                    let (sender, receiver) = mpsc::channel(samplerate.round().max(1.0) as usize);
+                    watches.push(SinkWatch::Channel(sender.clone()));
+                    (SampleSink::Channel(sender), AudioStream::from_receiver(samplerate, receiver))
+                }
+                Transport::RingBuffer => {
+                    // one second of samples
+                    let (prod, cons) = HeapRb::new(samplerate.round().max(1.0) as usize).split();
+                    let token = Arc::new(());
+                    watches.push(SinkWatch::Ring(Arc::downgrade(&token)));
+                    (SampleSink::Ring(prod), AudioStream::from_ring(samplerate, cons, token))
+                }
+            };
+            audiostream.dropped = Arc::clone(&dropped);
+            audiostream.live = true;
+            outputs.push(ChannelOutput { sink, pick, dropped });
+            streams.push(audiostream);
+        }
+        let (ready_tx, ready_rx) = std_mpsc::channel::<Result<()>>();
+
+        // cpal streams are not Send and stop when dropped, so a dedicated thread
+        // owns the stream until every AudioStream (the receivers) goes away
+        let (device, conf, buffer_size) = (self.device, self.conf, self.buffer_size);
+        thread::Builder::new()
+            .name("audio-capture".to_string())
+            .spawn(move || {
+                let stream = match AudioStreamBuilder::build_stream(&device, conf, buffer_size, outputs) {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        let _ = ready_tx.send(Err(e));
+                        return;
+                    }
+                };
+                if let Err(e) = stream.play() {
+                    let _ = ready_tx.send(Err(e.into()));
+                    return;
+                }
+                let _ = ready_tx.send(Ok(()));
 
-        Ok(AudioStream {
-            samplerate: self.samplerate,
-            stream: Arc::new(Mutex::new(Box::pin(outputstream)))
-        })
+                while watches.iter().any(|w| w.is_open()) {
+                    thread::sleep(Duration::from_millis(100));
+                }
+            })?;
+
+        ready_rx
+            .recv()
+            .map_err(|e| anyhow!("Audio capture thread stopped unexpectedly: {:}", e))??;
+
+        Ok(streams)
     }
 
     pub fn samplerate(&self) -> f64 {
@@ -215,10 +514,65 @@ impl AudioStreamBuilder {
 
 pub struct AudioStream {
     samplerate: f64,
-    stream: Arc<Mutex<Pin<Box<dyn FuturStream<Item = (f64, f64)> + Send>>>>
+    stream: Arc<Mutex<Pin<Box<dyn FuturStream<Item = (f64, f64)> + Send>>>>,
+    dropped: Arc<AtomicU64>,
+    // live sources do not wait for the reader
+    live: bool,
 }
 
 impl AudioStream {
+    // wrap any (time, value) sample channel, the stream ends once all senders are dropped
+    pub(crate) fn from_receiver(samplerate: f64, receiver: mpsc::Receiver<(f64, f64)>) -> Self {
+        let outputstream = stream::unfold(receiver, |mut receiver| async move {
+            match receiver.recv().await {
+                Some(sample) => Some((sample, receiver)),
+                None => None,
+            }
+        });
+
+        AudioStream {
+            samplerate,
+            stream: Arc::new(Mutex::new(Box::pin(outputstream))),
+            dropped: Arc::new(AtomicU64::new(0)),
+            live: false,
+        }
+    }
+
+    // the stream ends once the producer is gone, the token tells the producer we are alive
+    fn from_ring(samplerate: f64, cons: HeapCons<(f64, f64)>, token: Arc<()>) -> Self {
+        let outputstream = stream::unfold((cons, token), |(mut cons, token)| async move {
+            loop {
+                if let Some(sample) = cons.try_pop() {
+                    return Some((sample, (cons, token)));
+                }
+                if !cons.write_is_held() {
+                    return None;
+                }
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        });
+
+        AudioStream {
+            samplerate,
+            stream: Arc::new(Mutex::new(Box::pin(outputstream))),
+            dropped: Arc::new(AtomicU64::new(0)),
+            live: false,
+        }
+    }
+
+    // number of samples the source had to discard because processing fell behind
+    pub fn dropped_samples(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn drop_counter(&self) -> Arc<AtomicU64> {
+        Arc::clone(&self.dropped)
+    }
+
+    pub fn is_live(&self) -> bool {
+        self.live
+    }
+
     pub fn samplerate(&self) -> f64 {
         self.samplerate
     }
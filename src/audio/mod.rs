@@ -1,2 +1,5 @@
 pub mod io;
-pub mod track;
\ No newline at end of file
+pub mod track;
+pub mod wav;
+pub mod record;
+pub mod generator;
\ No newline at end of file
@@ -0,0 +1,52 @@
+use anyhow::{Context, Result};
+use hound::{SampleFormat, WavSpec, WavWriter};
+use serde::Serialize;
+use std::{fs::File, io::BufWriter, path::{Path, PathBuf}};
+use crate::audio::track::AudioTrack;
+
+pub struct Recorder {
+    path: PathBuf,
+    writer: WavWriter<BufWriter<File>>,
+}
+
+impl Recorder {
+    // create the wav file and write the metadata next to it as a json sidecar
+    pub fn new<P, M>(path: P, samplerate: f64, meta: &M) -> Result<Self>
+    where
+        P: AsRef<Path>,
+        M: Serialize,
+    {
+        let path = path.as_ref().to_path_buf();
+        let spec = WavSpec {
+            channels: 1,
+            sample_rate: samplerate.round() as u32,
+            bits_per_sample: 32,
+            sample_format: SampleFormat::Float,
+        };
+        let writer = WavWriter::create(&path, spec)
+            .context(format!("Unable to create recording {:}", path.display()))?;
+
+        let sidecar = path.with_extension("json");
+        let file = File::create(&sidecar)
+            .context(format!("Unable to create session metadata {:}", sidecar.display()))?;
+        serde_json::to_writer_pretty(file, meta)?;
+
+        Ok(Self { path, writer })
+    }
+
+    pub fn write_track(&mut self, track: &AudioTrack) -> Result<()> {
+        for value in track.get_volume() {
+            self.writer.write_sample(value as f32)?;
+        }
+        Ok(())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn finalize(self) -> Result<()> {
+        self.writer.finalize()?;
+        Ok(())
+    }
+}
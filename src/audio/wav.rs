@@ -0,0 +1,90 @@
+use anyhow::{anyhow, Context, Result};
+use hound::{SampleFormat, WavReader};
+use std::{fs::File, io::BufReader, path::Path, thread};
+use tokio::sync::mpsc;
+use crate::audio::io::{AudioStream, Channel};
+
+pub struct WavStreamBuilder {
+    samplerate: f64,
+    reader: WavReader<BufReader<File>>,
+    channel: Channel,
+}
+
+impl WavStreamBuilder {
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let reader = WavReader::open(path)
+            .context(format!("Unable to open wav file {:}", path.display()))?;
+        let samplerate: f64 = reader.spec().sample_rate as f64;
+
+        Ok(Self {
+            samplerate,
+            reader,
+            channel: Channel::Mix,
+        })
+    }
+
+    // channel used by build, multi-channel files are mixed down by default
+    pub fn with_channel(mut self, channel: Channel) -> Self {
+        self.channel = channel;
+        self
+    }
+
+    pub fn channels(&self) -> u16 {
+        self.reader.spec().channels
+    }
+
+    // read all samples scaled to [-1, 1] and pick the channel out of every frame
+    fn sample_reader(reader: WavReader<BufReader<File>>, sender: mpsc::Sender<(f64, f64)>, samplerate: f64, channel: Channel) -> Result<()> {
+        let spec = reader.spec();
+        let channels = spec.channels.max(1) as usize;
+
+        let samples: Box<dyn Iterator<Item = hound::Result<f64>>> = match spec.sample_format {
+            SampleFormat::Float => Box::new(reader.into_samples::<f32>().map(|s| s.map(|v| v as f64))),
+            SampleFormat::Int => {
+                let scale = 2.0_f64.powi(spec.bits_per_sample as i32 - 1);
+                Box::new(reader.into_samples::<i32>().map(move |s| s.map(|v| v as f64 / scale)))
+            }
+        };
+
+        let mut last_time: f64 = 0.0;
+        let mut frame: Vec<f64> = Vec::with_capacity(channels);
+        for sample in samples {
+            frame.push(sample?);
+            if frame.len() < channels {
+                continue;
+            }
+            last_time += 1.0 / samplerate;
+            let value = channel.value(&frame);
+            frame.clear();
+
+            // blocking send paces the reader to the processing, stop once the stream is dropped
+            if sender.blocking_send((last_time, value)).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn build(self) -> Result<AudioStream> {
+        let (sender, receiver) = mpsc::channel(10000);
+        let samplerate = self.samplerate;
+        let reader = self.reader;
+        let channel = self.channel;
+
+        thread::Builder::new()
+            .name("wav-reader".to_string())
+            .spawn(move || {
+                if let Err(e) = WavStreamBuilder::sample_reader(reader, sender, samplerate, channel) {
+                    eprintln!("an error occurred while reading wav file: {}", e);
+                }
+            })
+            .map_err(|e| anyhow!("Unable to start wav reader due to {:}", e))?;
+
+        Ok(AudioStream::from_receiver(samplerate, receiver))
+    }
+
+    pub fn samplerate(&self) -> f64 {
+        self.samplerate
+    }
+}
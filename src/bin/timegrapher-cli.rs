@@ -0,0 +1,328 @@
+use anyhow::{anyhow, Context, Result};
+use futures::future;
+use serde::Serialize;
+use std::env;
+use cpal::SampleFormat;
+use timegrapher::audio::io::{self as audioio, AudioStream, AudioStreamBuilder, Channel};
+use timegrapher::audio::wav::WavStreamBuilder;
+use timegrapher::signal::analyzer::{Analyzer, AnalyzerSettings};
+use timegrapher::signal::measure::Measurement;
+use timegrapher::signal::processor::Stage;
+use timegrapher::signal::rate::RateCalculator;
+
+const USAGE: &str = "Usage: timegrapher-cli [OPTIONS]
+
+Analyse a watch from a wav recording or a live input device.
+
+Options:
+  --wav <FILE>          analyse a wav file instead of a device
+  --host <NAME>         audio host to use (default: first available)
+  --device <NAME>       input device to use (default: first available)
+  --channel <CHANNEL>   left, right or mix of a multi-channel input, all analyses every
+                        channel of a device on its own (default: mix)
+  --samplerate <HZ>     device sample rate (default: device default)
+  --sample-format <FMT> device sample format: i8, i16, i32 or f32 (default: device default)
+  --buffer-size <N>     fixed device buffer size in frames (default: host default)
+  --duration <SECONDS>  how long to analyse (default: 30 for devices, whole file for wav)
+  --frame <SECONDS>     processing block length, 0.01 to 10 (default: 0.2)
+  --window <SECONDS>    analysis window the blocks are processed in, 0.1 to 60 (default: 2)
+  --bph <N>             beat rate, 0 for automatic detection (default: 0)
+  --lift-angle <DEG>    lift angle in degrees (default: 52)
+  --cutoff <DB>         envelope cutoff in dB (default: -60)
+  --no-denoiser         disable the speex denoiser
+  --noise-suppress <DB> maximum noise attenuation of the denoiser, zero or negative (default: -30)
+  --presence-gate <PCT> skip beats while the denoiser's speech probability is below this percent,
+                        needs the speex denoise stage (default: 0, off)
+  --chain <STAGES>      comma separated processing stages (default: denoise, envelope, cutoff)
+  --no-agc              disable automatic gain control
+  --agc-level <LEVEL>   loudness the gain control aims for, 1 to 32768 (default: 8000)
+  --json                print the results as json
+  --list-devices        list audio hosts, their input devices and supported configs
+  -h, --help            print this help";
+
+// the ranges the audio settings window allows, shorter blocks leave the envelope
+// without samples to work on
+const FRAME_RANGE: (f64, f64) = (0.01, 10.0);
+const WINDOW_RANGE: (f64, f64) = (0.1, 60.0);
+
+struct Options {
+    wav: Option<String>,
+    host: Option<String>,
+    device: Option<String>,
+    channel: Channel,
+    all_channels: bool,
+    samplerate: Option<u32>,
+    sample_format: Option<SampleFormat>,
+    buffer_size: Option<u32>,
+    duration: Option<f64>,
+    frame: f64,
+    json: bool,
+    list_devices: bool,
+    settings: AnalyzerSettings,
+}
+
+#[derive(Serialize)]
+struct Report {
+    source: String,
+    duration: f64,
+    dropped_samples: u64,
+    beats: usize,
+    bph: Option<u32>,
+    rate: Option<f64>,
+    beat_error: Option<f64>,
+    beat_error_std: Option<f64>,
+    amplitude: Option<f64>,
+}
+
+fn parse_value<T: std::str::FromStr>(args: &mut impl Iterator<Item = String>, flag: &str) -> Result<T> {
+    let value = args.next().ok_or(anyhow!("Missing value for {:}", flag))?;
+    value
+        .parse::<T>()
+        .map_err(|_| anyhow!("Invalid value '{:}' for {:}", value, flag))
+}
+
+fn parse_args() -> Result<Option<Options>> {
+    let mut options = Options {
+        wav: None,
+        host: None,
+        device: None,
+        channel: Channel::Mix,
+        all_channels: false,
+        samplerate: None,
+        sample_format: None,
+        buffer_size: None,
+        duration: None,
+        frame: 0.2,
+        json: false,
+        list_devices: false,
+        settings: AnalyzerSettings::default(),
+    };
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--wav" => options.wav = Some(parse_value(&mut args, &arg)?),
+            "--host" => options.host = Some(parse_value(&mut args, &arg)?),
+            "--device" => options.device = Some(parse_value(&mut args, &arg)?),
+            "--channel" => {
+                let name: String = parse_value(&mut args, &arg)?;
+                if name.eq_ignore_ascii_case("all") {
+                    options.all_channels = true;
+                } else {
+                    options.channel = name.parse()?;
+                }
+            }
+            "--samplerate" => options.samplerate = Some(parse_value(&mut args, &arg)?),
+            "--sample-format" => {
+                let name: String = parse_value(&mut args, &arg)?;
+                options.sample_format = Some(
+                    audioio::sample_format_from_str(&name)
+                        .ok_or(anyhow!("Unsupported sample format '{:}'", name))?,
+                );
+            }
+            "--buffer-size" => options.buffer_size = Some(parse_value(&mut args, &arg)?),
+            "--duration" => options.duration = Some(parse_value(&mut args, &arg)?),
+            "--frame" => options.frame = parse_value(&mut args, &arg)?,
+            "--window" => options.settings.window = parse_value(&mut args, &arg)?,
+            "--bph" => options.settings.bph = parse_value(&mut args, &arg)?,
+            "--lift-angle" => options.settings.lift_angle = parse_value(&mut args, &arg)?,
+            "--cutoff" => options.settings.cutoff = parse_value(&mut args, &arg)?,
+            "--chain" => {
+                let chain: String = parse_value(&mut args, &arg)?;
+                options.settings.chain = Stage::parse_chain(&chain)?;
+            }
+            "--no-denoiser" => options.settings.use_denoiser = false,
+            "--noise-suppress" => options.settings.noise_suppress = parse_value(&mut args, &arg)?,
+            "--presence-gate" => options.settings.presence_gate = parse_value(&mut args, &arg)?,
+            "--no-agc" => options.settings.use_agc = false,
+            "--agc-level" => options.settings.agc_level = parse_value(&mut args, &arg)?,
+            "--json" => options.json = true,
+            "--list-devices" => options.list_devices = true,
+            "-h" | "--help" => return Ok(None),
+            other => return Err(anyhow!("Unknown argument '{:}'\n\n{:}", other, USAGE)),
+        }
+    }
+    for (flag, value, (min, max)) in [
+        ("--frame", options.frame, FRAME_RANGE),
+        ("--window", options.settings.window, WINDOW_RANGE),
+    ] {
+        if !(min..=max).contains(&value) {
+            return Err(anyhow!("{:} has to be between {:} and {:} seconds, got {:}", flag, min, max, value));
+        }
+    }
+    if options.settings.presence_gate > 0 && !options.settings.presence_available() {
+        eprintln!("warning: --presence-gate is ignored, only the speex denoise stage reports a speech probability");
+    }
+    Ok(Some(options))
+}
+
+fn list_devices() -> Result<()> {
+    for con in audioio::get_connectors()? {
+        println!("{:}", con.name());
+        for dev in con.list_device_names().unwrap_or_default() {
+            println!("  {:}", dev);
+            for conf in con.list_input_configs(&dev).unwrap_or_default() {
+                let buffer = match conf.buffer_size {
+                    Some((min, max)) => format!("buffer {:}..{:} frames", min, max),
+                    None => "buffer size unknown".to_string(),
+                };
+                println!(
+                    "    {:} ch, {:}, {:}..{:} Hz, {:}",
+                    conf.channels, conf.sample_format, conf.min_samplerate, conf.max_samplerate, buffer
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+// returns the streams together with a name of their source, one stream unless all
+// channels of a device were asked for
+fn open_streams(options: &Options) -> Result<Vec<(String, AudioStream)>> {
+    if let Some(path) = &options.wav {
+        if options.all_channels {
+            return Err(anyhow!("--channel all needs an input device"));
+        }
+        let stream = WavStreamBuilder::new(path)?.with_channel(options.channel).build()?;
+        return Ok(vec![(path.clone(), stream)]);
+    }
+
+    let mut cons = audioio::get_connectors()?;
+    let index = match &options.host {
+        Some(host) => cons
+            .iter()
+            .position(|con| con.name().eq_ignore_ascii_case(host))
+            .ok_or(anyhow!("Unable to find audio host: {}", host))?,
+        None => 0,
+    };
+    if index >= cons.len() {
+        return Err(anyhow!("No audio hosts available"));
+    }
+    let con = cons.remove(index);
+
+    let device = match &options.device {
+        Some(device) => device.clone(),
+        None => con
+            .list_device_names()
+            .and_then(|devs| devs.first().cloned())
+            .ok_or(anyhow!("No input devices found on {}", con.name()))?,
+    };
+    let builder = AudioStreamBuilder::new(&con, &device)?
+        .with_format(options.samplerate, options.sample_format)?
+        .with_buffer_size(options.buffer_size);
+    let source = format!("{:}:{:}", con.name(), device);
+    if options.all_channels {
+        let streams = builder.build_channels()?;
+        return Ok(streams
+            .into_iter()
+            .enumerate()
+            .map(|(index, stream)| (format!("{:} channel {:}", source, index + 1), stream))
+            .collect());
+    }
+    Ok(vec![(source, builder.with_channel(options.channel).build()?)])
+}
+
+fn format_value(value: Option<f64>, format: impl Fn(f64) -> String) -> String {
+    value.map(format).unwrap_or("--".to_string())
+}
+
+fn print_report(report: &Report) {
+    println!("source:     {:}", report.source);
+    println!("duration:   {:.1} s", report.duration);
+    println!("dropped:    {:} samples", report.dropped_samples);
+    println!("beats:      {:}", report.beats);
+    println!("beat rate:  {:}", format_value(report.bph.map(|b| b as f64), |b| format!("{:.0} bph", b)));
+    println!("rate:       {:}", format_value(report.rate, |r| format!("{:+.1} s/d", r)));
+    println!(
+        "beat error: {:}",
+        match (report.beat_error, report.beat_error_std) {
+            (Some(be), Some(std)) => format!("{:.1} ms (±{:.1})", be, std),
+            _ => "--".to_string(),
+        }
+    );
+    println!("amplitude:  {:}", format_value(report.amplitude, |a| format!("{:.0}°", a)));
+}
+
+async fn analyze(options: &Options, source: String, stream: AudioStream) -> Report {
+    let samplerate = stream.samplerate();
+    let frame_size = ((options.frame * samplerate).round() as i64).max(1);
+    let duration = options
+        .duration
+        .unwrap_or(if options.wav.is_some() { f64::INFINITY } else { 30.0 });
+
+    let mut analyzer = Analyzer::new(options.settings.clone());
+    let mut measurement = Measurement::default();
+    let mut elapsed: f64 = 0.0;
+    while elapsed < duration {
+        let track = stream.get_track_by_framesize(frame_size).await;
+
+        // finite sources (wav files) end with a partial block
+        let finished = (track.track.len() as i64) < frame_size;
+        if track.track.is_empty() {
+            break;
+        }
+        elapsed += track.track.len() as f64 / samplerate;
+
+        let (_, result) = analyzer.push(track);
+        measurement = result;
+
+        if finished {
+            break;
+        }
+    }
+
+    // the rate is fitted over every beat of the session rather than the last frame,
+    // unless the noise could have caused half of them
+    let bph = measurement.bph;
+    let confident = measurement.trace.iter().filter(|beat| beat.is_confident()).count();
+    let rate = bph
+        .filter(|_| 2 * confident >= measurement.trace.len())
+        .and_then(|bph| RateCalculator::new(measurement.trace.clone(), bph).run_calculator())
+        .or(measurement.rate);
+
+    Report {
+        source,
+        duration: elapsed,
+        dropped_samples: stream.dropped_samples(),
+        beats: measurement.trace.len(),
+        bph,
+        rate,
+        beat_error: measurement.beat_error,
+        beat_error_std: measurement.beat_error_std,
+        amplitude: measurement.amplitude,
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let options = match parse_args()? {
+        Some(options) => options,
+        None => {
+            println!("{:}", USAGE);
+            return Ok(());
+        }
+    };
+
+    if options.list_devices {
+        return list_devices();
+    }
+
+    let streams = open_streams(&options).context("Unable to open audio source")?;
+    // the channels of a device are read side by side so that none of them backs up
+    let reports = future::join_all(streams.into_iter().map(|(source, stream)| analyze(&options, source, stream))).await;
+
+    if options.json {
+        match reports.as_slice() {
+            [report] => println!("{:}", serde_json::to_string_pretty(report)?),
+            reports => println!("{:}", serde_json::to_string_pretty(reports)?),
+        }
+    } else {
+        for (index, report) in reports.iter().enumerate() {
+            if index > 0 {
+                println!();
+            }
+            print_report(report);
+        }
+    }
+    Ok(())
+}
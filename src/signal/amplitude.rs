@@ -0,0 +1,105 @@
+use crate::audio::track::AudioTrack;
+use crate::signal::beats::{BeatEvent, QUIET_TIME};
+use std::f64::consts::PI;
+
+// range of balance amplitudes in degrees the drop is looked for in
+const MIN_AMPLITUDE: f64 = 90.0;
+const MAX_AMPLITUDE: f64 = 360.0;
+// sub-events reach at least this many times the highest noise before the beat
+const NOISE_MARGIN: f64 = 1.5;
+
+pub struct AmplitudeCalculator {
+    track: AudioTrack,
+    beats: Vec<BeatEvent>,
+    bph: u32,
+    lift_angle: f64,
+}
+
+impl AmplitudeCalculator {
+    // track is the BitCalculator envelope the beats were detected on, lift angle in degrees
+    pub fn new(track: AudioTrack, beats: Vec<BeatEvent>, bph: u32, lift_angle: f64) -> Self {
+        Self {
+            track,
+            beats,
+            bph,
+            lift_angle,
+        }
+    }
+
+    // time between unlock and drop at the given amplitude
+    fn lift_time(&self, amplitude: f64) -> f64 {
+        (self.lift_angle / (2.0 * amplitude)).min(1.0).asin() * 7200.0 / (PI * self.bph as f64)
+    }
+
+    // times of the unlock (first) and drop sub-event peaks inside one beat, the drop is
+    // only looked for where an amplitude from MIN_AMPLITUDE to MAX_AMPLITUDE puts it
+    fn sub_events(&self, time: &[f64], volu: &[f64], beat: &BeatEvent) -> Option<(f64, f64)> {
+        // the envelope right before the onset is all noise, sub-events have to stand out of it
+        let quiet_start = time.partition_point(|&t| t < beat.time - QUIET_TIME);
+        let quiet_end = time.partition_point(|&t| t < beat.time - 0.001);
+        if quiet_end <= quiet_start {
+            return None;
+        }
+        let level = NOISE_MARGIN * volu[quiet_start..quiet_end].iter().cloned().fold(0.0, f64::max);
+
+        // the search reaches past the longest lift time to see a drop that is out of range
+        let (shortest, longest) = (self.lift_time(MAX_AMPLITUDE), self.lift_time(MIN_AMPLITUDE));
+        let start = time.partition_point(|&t| t < beat.time - 0.002);
+        let end = time.partition_point(|&t| t < beat.time + 1.5 * longest + 0.002);
+        if end < start + 3 {
+            return None;
+        }
+
+        // a sub-event is the maximum within 0.5 ms to both sides, which skips the ripple
+        // of the rectified click on the rising edge
+        let samplerate = 1.0 / (time[start + 1] - time[start]);
+        let half = (0.0005 * samplerate).round().max(1.0) as usize;
+        let peaks: Vec<usize> = (start + 1..end - 1)
+            .filter(|&i| {
+                let lo = i.saturating_sub(half);
+                let hi = (i + half + 1).min(volu.len());
+                volu[i] > level
+                    && volu[lo..i].iter().all(|&v| v < volu[i])
+                    && volu[i + 1..hi].iter().all(|&v| v <= volu[i])
+            })
+            .collect();
+
+        // the drop is the loudest sub-event in its range, the ripple of its decay comes later
+        let unlock = time[*peaks.first()?];
+        let drop = peaks
+            .iter()
+            .cloned()
+            .filter(|&i| time[i] - unlock >= shortest && time[i] - unlock <= longest)
+            .max_by(|&a, &b| volu[a].total_cmp(&volu[b]))?;
+
+        // a louder sub-event after the range is the real drop of a balance swinging below
+        // MIN_AMPLITUDE, what was found in the range is then the impulse
+        if peaks.iter().any(|&i| time[i] - unlock > longest && volu[i] > volu[drop]) {
+            return None;
+        }
+        Some((unlock, time[drop]))
+    }
+
+    // amplitude in degrees for every confident beat where both sub-events were found
+    pub fn run_calculator(&self) -> Vec<f64> {
+        if self.bph == 0 || self.lift_angle <= 0.0 {
+            return Vec::new();
+        }
+        let time = self.track.get_time();
+        let volu = self.track.get_volume();
+
+        self.beats
+            .iter()
+            .filter(|beat| beat.is_confident())
+            .filter_map(|beat| self.sub_events(&time, &volu, beat))
+            .filter_map(|(unlock, drop)| {
+                // the balance crosses the lift angle in (drop - unlock) on a sine of period 2 * beat period
+                let phase = PI * (drop - unlock) * self.bph as f64 / 7200.0;
+                if phase <= 0.0 || phase >= PI / 2.0 {
+                    return None;
+                }
+                Some(self.lift_angle / (2.0 * phase.sin()))
+            })
+            .collect()
+    }
+}
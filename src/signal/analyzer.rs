@@ -0,0 +1,585 @@
+use crate::audio::track::AudioTrack;
+#[cfg(feature = "speexdsp")]
+use crate::signal::speexdsp;
+use crate::signal::{calculator, processor};
+use crate::signal::filter::IirFilter;
+use crate::signal::spectral::{NoiseProfile, SpectralDenoiser};
+use crate::signal::processor::{Chain, Processor, Stage};
+use crate::signal::amplitude::AmplitudeCalculator;
+use crate::signal::beat_error::BeatErrorCalculator;
+use crate::signal::beats::{BeatDetector, BeatEvent, Polarity};
+use crate::signal::bph::BphDetector;
+use crate::signal::measure::{Measurement, RollingStats, Spectrum};
+use crate::signal::rate::{beat_period, RateCalculator};
+use crate::signal::utils;
+use std::collections::VecDeque;
+
+// number of beats kept for the timegrapher trace
+const TRACE_LENGTH: usize = 2000;
+// seconds of published beats the rate is fitted over
+const RATE_WINDOW: f64 = 10.0;
+// frame length of the speex preprocessor in seconds
+#[cfg(feature = "speexdsp")]
+const DENOISER_FRAME: f64 = 0.02;
+// hop of the spectral denoiser in seconds, a tick opens the gain of the whole frame
+// it falls into, so longer frames let the noise through well before the onset
+const SPECTRAL_HOP: f64 = 0.005;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnalyzerSettings {
+    pub use_denoiser: bool,
+    // maximum noise attenuation of the denoiser in dB, zero or negative
+    pub noise_suppress: i32,
+    pub use_agc: bool,
+    // loudness the AGC aims for on the scale of 16 bit samples
+    pub agc_level: f64,
+    // speech probability of the denoiser in percent below which no beats are
+    // published, 0 publishes all beats
+    pub presence_gate: u8,
+    pub cutoff: f64,
+    // 0 selects automatic beat rate detection
+    pub bph: u32,
+    pub lift_angle: f64,
+    // length of the analysis window in seconds, blocks are analysed together with
+    // the preceding samples up to this length
+    pub window: f64,
+    // stages turning the raw window into the envelope the beats are detected on
+    pub chain: Vec<Stage>,
+    // noise learned without the watch, the denoise stages subtract it when it was
+    // learned at the samplerate of the stream
+    pub noise_profile: Option<NoiseProfile>,
+}
+
+impl AnalyzerSettings {
+    // the presence gate works on the speech probability of the speex denoiser, spectral
+    // subtraction takes its place without speexdsp and once a noise profile was learned
+    pub fn presence_available(&self) -> bool {
+        cfg!(feature = "speexdsp") && self.noise_profile.is_none() && self.chain.contains(&Stage::Denoise)
+    }
+}
+
+impl Default for AnalyzerSettings {
+    fn default() -> Self {
+        Self {
+            use_denoiser: true,
+            noise_suppress: -30,
+            use_agc: true,
+            agc_level: 8000.0,
+            presence_gate: 0,
+            cutoff: -60.0,
+            bph: 0,
+            lift_angle: 52.0,
+            window: 2.0,
+            chain: Stage::default_chain(),
+            noise_profile: None,
+        }
+    }
+}
+
+// streaming processing chain from raw blocks to the measurements, every block is
+// analysed together with the previous samples of the window so that beats on block
+// edges are seen whole, beats are only published once and the detector state
+// (last beat, tick/tock parity, beat rate) and the statistics carry over between blocks
+pub struct Analyzer {
+    settings: AnalyzerSettings,
+    // raw samples of the current analysis window, oldest first
+    window: VecDeque<(f64, f64)>,
+    // the same window after the streaming stages
+    filtered: VecDeque<(f64, f64)>,
+    // leading stages of the chain that run once on every block and keep their state,
+    // built with the first block. Speex is not Send, so the analyzer stays on one thread
+    stream_chain: Option<Chain>,
+    samplerate: f64,
+    beat_error_stats: RollingStats,
+    amplitude_stats: RollingStats,
+    detected_bph: Option<u32>,
+    last_beat: Option<BeatEvent>,
+    parity: u64,
+    trace: VecDeque<BeatEvent>,
+    // spectrum and speech probability of the denoiser after the last block
+    spectrum: Option<Spectrum>,
+    presence: Option<f64>,
+    // seconds still to learn the noise from and the raw samples collected so far
+    noise_learning: Option<(f64, Vec<f64>)>,
+    learned_noise: Option<NoiseProfile>,
+}
+
+impl Analyzer {
+    pub fn new(settings: AnalyzerSettings) -> Self {
+        Self {
+            settings,
+            window: VecDeque::new(),
+            filtered: VecDeque::new(),
+            stream_chain: None,
+            samplerate: 0.0,
+            beat_error_stats: RollingStats::new(100),
+            amplitude_stats: RollingStats::new(100),
+            detected_bph: None,
+            last_beat: None,
+            parity: 0,
+            trace: VecDeque::with_capacity(TRACE_LENGTH),
+            spectrum: None,
+            presence: None,
+            noise_learning: None,
+            learned_noise: None,
+        }
+    }
+
+    // takes effect with the next block, the beat history is kept but the statistics
+    // restart when they would mix results of different beat rates or lift angles
+    pub fn update_settings(&mut self, settings: AnalyzerSettings) {
+        if settings.bph != self.settings.bph || settings.lift_angle != self.settings.lift_angle {
+            self.beat_error_stats.clear();
+            self.amplitude_stats.clear();
+        }
+        // the streaming stages start over, the filtered window keeps what they produced so far
+        let denoiser = |s: &AnalyzerSettings| (s.use_denoiser, s.noise_suppress, s.use_agc, s.agc_level);
+        if Stage::split_chain(&settings.chain).0 != Stage::split_chain(&self.settings.chain).0
+            || denoiser(&settings) != denoiser(&self.settings)
+            || settings.noise_profile != self.settings.noise_profile
+        {
+            self.stream_chain = None;
+        }
+        self.settings = settings;
+    }
+
+    // build the given stages with the levels from the settings
+    pub fn build_chain(settings: &AnalyzerSettings, stages: &[Stage], samplerate: f64) -> Chain {
+        stages.iter().fold(Chain::new(), |chain, stage| {
+            let processor: Box<dyn Processor> = match stage.clone() {
+                Stage::Denoise => Analyzer::denoiser(settings, samplerate),
+                Stage::Spectral => Box::new(Analyzer::spectral_denoiser(settings, samplerate)),
+                Stage::Envelope => Box::new(calculator::BitCalculator::new(AudioTrack::new())),
+                Stage::Cutoff => {
+                    let cutoff = settings.cutoff;
+                    Box::new(move |track: &AudioTrack| utils::cutt_off(track, cutoff))
+                }
+                Stage::Gain(gain) => Box::new(move |track: &AudioTrack| utils::apply_gain(track, gain)),
+                Stage::RemoveMean => Box::new(utils::remove_mean),
+                Stage::Abs => Box::new(utils::abs),
+                Stage::Diff => Box::new(utils::apply_diff),
+                Stage::SlidingMax(window) => Box::new(move |track: &AudioTrack| utils::sliding_max(track, window)),
+                Stage::SlidingMean(window) => Box::new(move |track: &AudioTrack| utils::sliding_mean(track, window)),
+                Stage::HighPass(freq) => Box::new(IirFilter::highpass(freq, samplerate)),
+                Stage::LowPass(freq) => Box::new(IirFilter::lowpass(freq, samplerate)),
+                Stage::BandPass(low, high) => Box::new(IirFilter::bandpass(low, high, samplerate)),
+                Stage::FftLowPass(freq) => Box::new(processor::lowpass(freq)),
+            };
+            chain.with_stage(processor)
+        })
+    }
+
+    // speex cannot subtract a fixed noise spectrum, a learned profile hands the stage
+    // over to spectral subtraction
+    #[cfg(feature = "speexdsp")]
+    fn denoiser(settings: &AnalyzerSettings, samplerate: f64) -> Box<dyn Processor> {
+        let learned = settings
+            .noise_profile
+            .as_ref()
+            .is_some_and(|profile| profile.fits(Analyzer::spectral_hop(samplerate), samplerate));
+        if settings.use_denoiser && learned {
+            return Box::new(Analyzer::spectral_denoiser(settings, samplerate));
+        }
+        let speex = || -> anyhow::Result<speexdsp::Denoiser> {
+            let frame_size = (DENOISER_FRAME * samplerate).round().max(1.0) as i32;
+            let mut denoiser = speexdsp::Denoiser::new(frame_size, samplerate as i32)?;
+            denoiser.set_denoise(settings.use_denoiser)?;
+            denoiser.set_noise_suppress(settings.noise_suppress)?;
+            denoiser.set_agc(settings.use_agc)?;
+            denoiser.set_agc_level(settings.agc_level as f32)?;
+            Ok(denoiser)
+        };
+        match speex() {
+            Ok(denoiser) => Box::new(denoiser),
+            Err(err) => {
+                log::warn!("Denoise stage skipped: {:}", err);
+                Box::new(|track: &AudioTrack| track.clone())
+            }
+        }
+    }
+
+    #[cfg(not(feature = "speexdsp"))]
+    fn denoiser(settings: &AnalyzerSettings, samplerate: f64) -> Box<dyn Processor> {
+        if !settings.use_denoiser {
+            return Box::new(|track: &AudioTrack| track.clone());
+        }
+        Box::new(Analyzer::spectral_denoiser(settings, samplerate))
+    }
+
+    fn spectral_hop(samplerate: f64) -> usize {
+        (SPECTRAL_HOP * samplerate).round().max(1.0) as usize
+    }
+
+    // a profile learned at another samplerate is left out, the noise is tracked instead
+    fn spectral_denoiser(settings: &AnalyzerSettings, samplerate: f64) -> SpectralDenoiser {
+        let denoiser = || SpectralDenoiser::new(Analyzer::spectral_hop(samplerate), samplerate).with_suppression(settings.noise_suppress as f64);
+        settings
+            .noise_profile
+            .clone()
+            .and_then(|profile| denoiser().with_profile(profile).ok())
+            .unwrap_or_else(denoiser)
+    }
+
+    // collect the next seconds of raw samples, the watch must be off the microphone,
+    // the profile is applied and handed out by take_learned_noise once complete
+    pub fn learn_noise(&mut self, seconds: f64) {
+        self.noise_learning = Some((seconds, Vec::new()));
+    }
+
+    pub fn take_learned_noise(&mut self) -> Option<NoiseProfile> {
+        self.learned_noise.take()
+    }
+
+    fn continue_learning(&mut self, block: &AudioTrack) {
+        let Some((seconds, samples)) = self.noise_learning.as_mut() else {
+            return;
+        };
+        samples.extend(block.get_volume());
+        if (samples.len() as f64) < *seconds * self.samplerate {
+            return;
+        }
+        let samplerate = self.samplerate;
+        let silence = AudioTrack::from_rate_track(
+            samplerate,
+            samples.iter().enumerate().map(|(i, &v)| (i as f64 / samplerate, v)).collect(),
+        );
+        self.noise_learning = None;
+
+        let mut denoiser = SpectralDenoiser::new(Analyzer::spectral_hop(self.samplerate), self.samplerate);
+        match denoiser.learn(&silence) {
+            Ok(()) => {
+                self.settings.noise_profile = denoiser.profile().cloned();
+                self.learned_noise = self.settings.noise_profile.clone();
+                self.stream_chain = None;
+            }
+            Err(err) => log::warn!("Noise not learned: {:}", err),
+        }
+    }
+
+    // raw samples of the current analysis window
+    pub fn window(&self) -> AudioTrack {
+        AudioTrack::from_rate_track(self.samplerate, self.window.iter().cloned().collect())
+    }
+
+    // beats of the window that were not published yet and lie far enough from the window
+    // edges to be complete, with the polarity continued from the last published beat
+    fn new_beats(&mut self, beats: &[BeatEvent], bph: u32, start: f64, end: f64) -> Vec<BeatEvent> {
+        let period = if bph == 0 { 0.0 } else { beat_period(bph) };
+        // the amplitude needs the sub-events up to 0.3 beat periods after the onset
+        let guard = if bph == 0 { 0.05 } else { 0.3 * period + 0.002 };
+        let holdoff = (0.5 * period).max(0.05);
+
+        let mut published = Vec::new();
+        for beat in beats.iter() {
+            if beat.time < start + 0.005 || beat.time > end - guard {
+                continue;
+            }
+            if let Some(last) = self.last_beat {
+                if beat.time - last.time < holdoff {
+                    continue;
+                }
+                let steps = if period > 0.0 { ((beat.time - last.time) / period).round().max(1.0) as u64 } else { 1 };
+                self.parity += steps;
+            }
+            let beat = BeatEvent {
+                polarity: Polarity::from_parity(self.parity),
+                index: self.parity,
+                ..*beat
+            };
+            self.last_beat = Some(beat);
+            published.push(beat);
+        }
+        published
+    }
+
+    // add a block of raw samples, returns the envelope of the window and the measurements
+    pub fn push(&mut self, block: AudioTrack) -> (AudioTrack, Measurement) {
+        self.samplerate = block.get_sample_rate();
+        let sampling_rate = self.samplerate;
+        self.continue_learning(&block);
+
+        // the streaming stages see every sample once, their output is moved back by
+        // their latency so that beat times stay on the time axis of the raw signal
+        let (stream_stages, window_stages) = Stage::split_chain(&self.settings.chain);
+        let stream_chain = self
+            .stream_chain
+            .get_or_insert_with(|| {
+                let chain = Analyzer::build_chain(&self.settings, stream_stages, sampling_rate);
+                if self.settings.presence_gate > 0 && chain.presence().is_none() {
+                    log::warn!("Presence gate is off, no denoise stage reports a speech probability");
+                }
+                chain
+            });
+        let latency = stream_chain.latency();
+        let filtered = stream_chain.process_block(&block);
+        self.spectrum = stream_chain.spectrum();
+        self.presence = stream_chain.presence();
+
+        let window_size = ((self.settings.window * self.samplerate).round() as usize).max(block.track.len());
+        self.window.extend(block.track);
+        self.filtered.extend(filtered.track.iter().map(|&(t, v)| (t - latency, v)));
+        let excess = self.window.len().saturating_sub(window_size);
+        self.window.drain(..excess);
+        let excess = self.filtered.len().saturating_sub(window_size);
+        self.filtered.drain(..excess);
+
+        let ctl = &self.settings;
+        let gaps = utils::find_gaps(&self.window());
+        let mut track = AudioTrack::from_rate_track(sampling_rate, self.filtered.iter().cloned().collect());
+        let (start, end) = match (track.track.first(), track.track.last()) {
+            (Some(&(start, _)), Some(&(end, _))) => (start, end),
+            _ => return (track, self.measurement(Vec::new(), None, 0)),
+        };
+
+        track = Analyzer::build_chain(ctl, window_stages, sampling_rate).process(&track);
+
+        // keep the last detected rate when a window is too noisy to detect one
+        if ctl.bph == 0 {
+            if let Some(bph) = BphDetector::new(track.clone()).run_detector() {
+                self.detected_bph = Some(bph);
+            }
+        }
+        let bph = if ctl.bph == 0 { self.detected_bph.unwrap_or(0) } else { ctl.bph };
+        let lift_angle = ctl.lift_angle;
+
+        let beats = BeatDetector::new(track.clone()).run_detector();
+
+        // beats next to dropped samples are unreliable, they are published once the gap left the window,
+        // with the presence gate on nothing is published while the watch is not heard
+        let present = match (ctl.presence_gate, self.presence) {
+            (0, _) | (_, None) => true,
+            (gate, Some(presence)) => 100.0 * presence >= gate as f64,
+        };
+        if gaps.is_empty() && present {
+            let published = self.new_beats(&beats, bph, start, end);
+            for beat in published.iter() {
+                if self.trace.len() == TRACE_LENGTH {
+                    self.trace.pop_front();
+                }
+                self.trace.push_back(*beat);
+            }
+
+            if !published.is_empty() {
+                // the two beats before the new ones close the first beat error pair
+                let tail = self.trace.len().saturating_sub(published.len() + 2);
+                let recent: Vec<BeatEvent> = self.trace.iter().skip(tail).cloned().collect();
+                self.beat_error_stats.extend(&BeatErrorCalculator::new(recent, bph).run_calculator());
+                self.amplitude_stats
+                    .extend(&AmplitudeCalculator::new(track.clone(), published, bph, lift_angle).run_calculator());
+            }
+        }
+
+        let measurement = self.measurement(beats, Some(bph).filter(|&bph| bph > 0), gaps.len());
+        (track, measurement)
+    }
+
+    fn measurement(&self, beats: Vec<BeatEvent>, bph: Option<u32>, gaps: usize) -> Measurement {
+        // the rate is fitted over the beats of the last RATE_WINDOW seconds
+        let recent: Vec<BeatEvent> = match self.last_beat {
+            Some(last) => self.trace.iter().filter(|beat| last.time - beat.time <= RATE_WINDOW).cloned().collect(),
+            None => Vec::new(),
+        };
+        // when the noise could have caused half of the recent beats none of the
+        // results can be trusted
+        let confident = recent.iter().filter(|beat| beat.is_confident()).count();
+        let heard = 2 * confident >= recent.len();
+        let rate = match bph {
+            Some(bph) if heard => RateCalculator::new(recent, bph).run_calculator(),
+            _ => None,
+        };
+
+        Measurement {
+            beats,
+            trace: self.trace.iter().cloned().collect(),
+            bph,
+            rate,
+            beat_error: self.beat_error_stats.mean().filter(|_| heard),
+            beat_error_std: self.beat_error_stats.std().filter(|_| heard),
+            amplitude: self.amplitude_stats.mean().filter(|_| heard),
+            gaps,
+            spectrum: self.spectrum.clone(),
+            presence: self.presence,
+            learning_noise: self.noise_learning.is_some(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::generator::{SignalGenerator, WatchParams};
+    use crate::signal::bph::STANDARD_BPH;
+
+    // the accuracy the readout is trusted with on a clean signal
+    const RATE_TOLERANCE: f64 = 0.1;
+    const BEAT_ERROR_TOLERANCE: f64 = 0.01;
+    const AMPLITUDE_TOLERANCE: f64 = 1.0;
+
+    // stream `seconds` of the generated watch through the analyzer in blocks of 0.2 s
+    fn analyze(params: WatchParams, chain: &str, seconds: f64) -> Measurement {
+        let settings = AnalyzerSettings {
+            bph: params.bph,
+            lift_angle: params.lift_angle,
+            chain: Stage::parse_chain(chain).unwrap(),
+            ..Default::default()
+        };
+        analyze_with(params, settings, seconds)
+    }
+
+    fn analyze_with(params: WatchParams, settings: AnalyzerSettings, seconds: f64) -> Measurement {
+        let mut generator = SignalGenerator::new(params);
+        let mut analyzer = Analyzer::new(settings);
+        let blocks = (seconds / 0.2).round() as usize;
+        (0..blocks)
+            .map(|_| analyzer.push(generator.generate(0.2)).1)
+            .last()
+            .unwrap()
+    }
+
+    #[test]
+    fn measures_generated_watches() {
+        for (bph, rate, beat_error, amplitude) in [
+            (18000, 3.0, 0.0, 300.0),
+            (21600, -25.0, 1.2, 250.0),
+            (28800, 10.0, 1.0, 280.0),
+            (36000, 3.0, 0.3, 260.0),
+        ] {
+            let params = WatchParams {
+                bph,
+                rate,
+                beat_error,
+                amplitude,
+                samplerate: 48000.0,
+                ..Default::default()
+            };
+            let measurement = analyze(params, "envelope, cutoff", 14.0);
+
+            assert_eq!(measurement.gaps, 0);
+            let measured = measurement.rate.unwrap();
+            assert!((measured - rate).abs() < RATE_TOLERANCE, "{bph} bph: rate {measured} s/d, expected {rate}");
+            let measured = measurement.beat_error.unwrap();
+            assert!(
+                (measured - beat_error).abs() < BEAT_ERROR_TOLERANCE,
+                "{bph} bph: beat error {measured} ms, expected {beat_error}"
+            );
+            let measured = measurement.amplitude.unwrap();
+            assert!(
+                (measured - amplitude).abs() < AMPLITUDE_TOLERANCE,
+                "{bph} bph: amplitude {measured}°, expected {amplitude}"
+            );
+        }
+    }
+
+    #[test]
+    fn detects_the_beat_rate() {
+        // half and double rates (14400 against 28800 and 43200) correlate at the period too
+        for bph in STANDARD_BPH {
+            let params = WatchParams {
+                bph,
+                rate: 10.0,
+                beat_error: 0.5,
+                ..Default::default()
+            };
+            let settings = AnalyzerSettings {
+                bph: 0,
+                chain: Stage::parse_chain("envelope, cutoff").unwrap(),
+                ..Default::default()
+            };
+            assert_eq!(analyze_with(params, settings, 4.0).bph, Some(bph));
+        }
+    }
+
+    #[test]
+    fn fits_the_rate_over_the_whole_trace() {
+        // the drift adds up to more than half a beat period over the session
+        for rate in [300.0, -300.0] {
+            let params = WatchParams {
+                rate,
+                beat_error: 0.5,
+                ..Default::default()
+            };
+            let bph = params.bph;
+            let measurement = analyze(params, "envelope, cutoff", 30.0);
+
+            let measured = RateCalculator::new(measurement.trace, bph).run_calculator().unwrap();
+            assert!((measured - rate).abs() < RATE_TOLERANCE, "rate {measured} s/d, expected {rate}");
+        }
+    }
+
+    #[test]
+    fn drowned_beats_give_no_results() {
+        for bph in [18000, 28800, 36000] {
+            let params = WatchParams {
+                bph,
+                rate: 10.0,
+                beat_error: 1.0,
+                noise: 0.01,
+                samplerate: 48000.0,
+                ..Default::default()
+            };
+            let measurement = analyze(params, "envelope, cutoff", 14.0);
+
+            assert_eq!(measurement.rate, None, "{bph} bph");
+            assert_eq!(measurement.beat_error, None, "{bph} bph");
+            assert_eq!(measurement.amplitude, None, "{bph} bph");
+        }
+    }
+
+    #[test]
+    fn measures_amplitude_in_noise() {
+        for amplitude in [100.0, 120.0, 180.0, 250.0, 300.0] {
+            let params = WatchParams {
+                amplitude,
+                noise: 0.005,
+                samplerate: 48000.0,
+                ..Default::default()
+            };
+            let measured = analyze(params, "envelope, cutoff", 6.0).amplitude.unwrap();
+            assert!((measured - amplitude).abs() < 5.0, "amplitude {measured}°, expected {amplitude}");
+        }
+
+        // a drop too late for the search range must not be mistaken for the impulse
+        let params = WatchParams {
+            amplitude: 70.0,
+            noise: 0.005,
+            samplerate: 48000.0,
+            ..Default::default()
+        };
+        assert_eq!(analyze(params, "envelope, cutoff", 6.0).amplitude, None);
+    }
+
+    #[test]
+    fn keeps_up_at_192_khz() {
+        let params = WatchParams {
+            rate: 5.0,
+            samplerate: 192000.0,
+            ..Default::default()
+        };
+        let measurement = analyze(params, "envelope, cutoff", 4.0);
+
+        assert_eq!(measurement.gaps, 0);
+        let measured = measurement.rate.unwrap();
+        assert!((measured - 5.0).abs() < RATE_TOLERANCE, "rate {measured} s/d, expected 5");
+    }
+
+    #[test]
+    fn spectral_denoiser_keeps_the_timing() {
+        for (bph, rate, beat_error) in [(28800, 10.0, 1.0), (36000, 3.0, 0.3)] {
+            let params = WatchParams {
+                bph,
+                rate,
+                beat_error,
+                noise: 0.003,
+                samplerate: 48000.0,
+                ..Default::default()
+            };
+            let raw = analyze(params.clone(), "envelope, cutoff", 14.0);
+            let denoised = analyze(params, "spectral, envelope, cutoff", 14.0);
+
+            let (raw_rate, rate) = (raw.rate.unwrap(), denoised.rate.unwrap());
+            assert!((rate - raw_rate).abs() < 0.5, "{bph} bph: rate {rate} s/d, raw {raw_rate}");
+            let (raw_error, error) = (raw.beat_error.unwrap(), denoised.beat_error.unwrap());
+            assert!((error - raw_error).abs() < 0.03, "{bph} bph: beat error {error} ms, raw {raw_error}");
+        }
+    }
+}
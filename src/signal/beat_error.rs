@@ -0,0 +1,39 @@
+use crate::signal::beats::BeatEvent;
+use crate::signal::rate::beat_period;
+
+pub struct BeatErrorCalculator {
+    beats: Vec<BeatEvent>,
+    bph: u32,
+}
+
+impl BeatErrorCalculator {
+    pub fn new(beats: Vec<BeatEvent>, bph: u32) -> Self {
+        Self { beats, bph }
+    }
+
+    // beat error in ms for every pair of neighbouring tick-tock / tock-tick intervals,
+    // beats the noise could have caused leave a gap
+    pub fn run_calculator(&self) -> Vec<f64> {
+        let beats: Vec<&BeatEvent> = self.beats.iter().filter(|b| b.is_confident()).collect();
+        if self.bph == 0 {
+            return Vec::new();
+        }
+        let period = beat_period(self.bph);
+
+        // intervals spanning a missed beat can not be compared
+        let is_single = |dt: f64| (dt / period - 1.0).abs() < 0.5;
+
+        beats
+            .windows(3)
+            .filter_map(|w| {
+                let first = w[1].time - w[0].time;
+                let second = w[2].time - w[1].time;
+                if is_single(first) && is_single(second) && w[0].polarity != w[1].polarity {
+                    Some(1000.0 * (first - second).abs() / 2.0)
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,165 @@
+use crate::audio::track::AudioTrack;
+use crate::signal::utils;
+
+// beats below this confidence are shown but left out of the measurements
+pub const MIN_CONFIDENCE: f64 = 0.75;
+// seconds before the onset that are compared with the beat, ending 1 ms before it
+pub const QUIET_TIME: f64 = 0.02;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    Tick,
+    Tock,
+}
+
+impl Polarity {
+    pub fn from_parity(parity: u64) -> Self {
+        if parity.is_multiple_of(2) {
+            Polarity::Tick
+        } else {
+            Polarity::Tock
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BeatEvent {
+    // onset time of the beat in seconds (stream time)
+    pub time: f64,
+    // peak value of the envelope within the beat
+    pub amplitude: f64,
+    pub polarity: Polarity,
+    // number of the beat counted in nominal periods from the first one, missed beats
+    // keep their number
+    pub index: u64,
+    // 0..1 measure of how far the peak stands above the envelope right before the
+    // onset, close to 0 when the noise there reaches as high
+    pub confidence: f64,
+}
+
+impl BeatEvent {
+    pub fn is_confident(&self) -> bool {
+        self.confidence >= MIN_CONFIDENCE
+    }
+}
+
+pub struct BeatDetector {
+    track: AudioTrack,
+    threshold: f64,
+    holdoff: f64,
+}
+
+impl BeatDetector {
+    // track is expected to be the envelope produced by BitCalculator::run_calculator
+    pub fn new(track: AudioTrack) -> Self {
+        Self {
+            track,
+            threshold: 0.3,
+            holdoff: 0.05,
+        }
+    }
+
+    // onset level as a fraction of the way from the envelope floor to its maximum
+    pub fn with_threshold(mut self, threshold: f64) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    // minimal time in seconds between two beats
+    pub fn with_holdoff(mut self, holdoff: f64) -> Self {
+        self.holdoff = holdoff;
+        self
+    }
+
+    fn median(values: &[f64]) -> Option<f64> {
+        if values.is_empty() {
+            return None;
+        }
+        let mut sorted = values.to_vec();
+        sorted.sort_by(|a, b| a.total_cmp(b));
+        Some(sorted[sorted.len() / 2])
+    }
+
+    // find onset, peak and confidence of every beat, polarity is assigned afterwards
+    fn find_onsets(&self) -> Vec<(f64, f64, f64)> {
+        let time = self.track.get_time();
+        let volu = self.track.get_volume();
+        let track_len = volu.len();
+
+        let (_, max) = utils::get_min_max(&self.track);
+        if track_len < 2 || max <= 0.0 {
+            return Vec::new();
+        }
+        let floor = utils::get_mean(&self.track);
+        let level = floor + self.threshold * (max - floor);
+
+        let samplerate = self.track.get_sample_rate();
+        let holdoff_size = (self.holdoff * samplerate).round() as usize;
+        let quiet_size = (QUIET_TIME * samplerate).round() as usize;
+        let margin = (0.001 * samplerate).round() as usize;
+
+        let mut onsets = Vec::new();
+        let mut last_onset: Option<f64> = None;
+        let mut ind = 1;
+        while ind < track_len {
+            let (v0, v1) = (volu[ind - 1], volu[ind]);
+            if v0 < level && v1 >= level {
+                // interpolate the exact crossing of the onset level
+                let onset = time[ind - 1] + (level - v0) / (v1 - v0) * (time[ind] - time[ind - 1]);
+
+                if last_onset.is_none_or(|last| onset - last >= self.holdoff) {
+                    let end = (ind + holdoff_size).min(track_len);
+                    let peak = volu[ind..end]
+                        .iter()
+                        .cloned()
+                        .max_by(|a, b| a.total_cmp(b))
+                        .unwrap_or(v1);
+                    let quiet = volu[ind.saturating_sub(quiet_size)..ind.saturating_sub(margin)]
+                        .iter()
+                        .cloned()
+                        .fold(floor, f64::max);
+                    let confidence = if peak > floor {
+                        (1.0 - (quiet - floor) / (peak - floor)).clamp(0.0, 1.0)
+                    } else {
+                        0.0
+                    };
+
+                    onsets.push((onset, peak, confidence));
+                    last_onset = Some(onset);
+                    ind = end.max(ind + 1);
+                    continue;
+                }
+            }
+            ind += 1;
+        }
+        onsets
+    }
+
+    pub fn run_detector(&self) -> Vec<BeatEvent> {
+        let onsets = self.find_onsets();
+
+        // polarity alternates between beats, a missed beat shows up as a doubled interval
+        let intervals: Vec<f64> = onsets.windows(2).map(|w| w[1].0 - w[0].0).collect();
+        let period = BeatDetector::median(&intervals);
+
+        let mut parity: u64 = 0;
+        let mut beats: Vec<BeatEvent> = Vec::with_capacity(onsets.len());
+        for (ind, &(time, amplitude, confidence)) in onsets.iter().enumerate() {
+            if ind > 0 {
+                let steps = match period {
+                    Some(p) if p > 0.0 => ((time - onsets[ind - 1].0) / p).round().max(1.0) as u64,
+                    _ => 1,
+                };
+                parity += steps;
+            }
+            beats.push(BeatEvent {
+                time,
+                amplitude,
+                polarity: Polarity::from_parity(parity),
+                index: parity,
+                confidence,
+            });
+        }
+        beats
+    }
+}
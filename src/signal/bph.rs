@@ -0,0 +1,84 @@
+use crate::audio::track::AudioTrack;
+use crate::signal::rate::beat_period;
+
+pub const STANDARD_BPH: [u32; 8] = [14400, 18000, 19800, 21600, 25200, 28800, 36000, 43200];
+
+pub struct BphDetector {
+    track: AudioTrack,
+    min_score: f64,
+}
+
+impl BphDetector {
+    // track is expected to be the envelope produced by BitCalculator::run_calculator
+    pub fn new(track: AudioTrack) -> Self {
+        Self {
+            track,
+            min_score: 0.1,
+        }
+    }
+
+    // minimal autocorrelation contrast needed to accept a beat rate
+    pub fn with_min_score(mut self, min_score: f64) -> Self {
+        self.min_score = min_score;
+        self
+    }
+
+    // sum the envelope into 1 ms bins and remove the mean, returns the bin rate as well
+    fn decimate(&self) -> (f64, Vec<f64>) {
+        let samplerate = self.track.get_sample_rate();
+        let bin = (0.001 * samplerate).round().max(1.0) as usize;
+
+        let volu = self.track.get_volume();
+        let binned: Vec<f64> = volu.chunks_exact(bin).map(|c| c.iter().sum()).collect();
+
+        let len = binned.len().max(1) as f64;
+        let mean = binned.iter().sum::<f64>() / len;
+        (samplerate / bin as f64, binned.iter().map(|v| v - mean).collect())
+    }
+
+    // normalised autocorrelation at a single lag
+    fn autocorrelation(signal: &[f64], energy: f64, lag: usize) -> f64 {
+        if lag >= signal.len() {
+            return 0.0;
+        }
+        let sum: f64 = signal.iter().zip(signal[lag..].iter()).map(|(a, b)| a * b).sum();
+        sum / energy
+    }
+
+    // highest autocorrelation within 2% around the given period, to allow for rate errors
+    fn peak_autocorrelation(signal: &[f64], energy: f64, rate: f64, period: f64) -> f64 {
+        let lag_min = (0.98 * period * rate).floor() as usize;
+        let lag_max = (1.02 * period * rate).ceil() as usize;
+        (lag_min..=lag_max)
+            .map(|lag| BphDetector::autocorrelation(signal, energy, lag))
+            .fold(f64::MIN, f64::max)
+    }
+
+    pub fn run_detector(&self) -> Option<u32> {
+        let (rate, signal) = self.decimate();
+        let energy: f64 = signal.iter().map(|v| v * v).sum();
+        if energy <= 0.0 {
+            return None;
+        }
+
+        // a beat train correlates at its period but not at 1.5 periods, which separates
+        // a rate from its half (e.g. 28800 from 14400) that correlates at both
+        let mut best: Option<(u32, f64)> = None;
+        for &bph in STANDARD_BPH.iter() {
+            let period = beat_period(bph);
+            if (signal.len() as f64) < 3.0 * period * rate {
+                continue;
+            }
+            let score = BphDetector::peak_autocorrelation(&signal, energy, rate, period)
+                - BphDetector::peak_autocorrelation(&signal, energy, rate, 1.5 * period);
+            if best.is_none_or(|(_, s)| score > s) {
+                best = Some((bph, score));
+            }
+        }
+
+        match best {
+            Some((bph, score)) if score >= self.min_score => Some(bph),
+            _ => None,
+        }
+    }
+}
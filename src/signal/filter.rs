@@ -0,0 +1,177 @@
+use crate::audio::track::AudioTrack;
+use crate::signal::processor::Processor;
+use std::f64::consts::PI;
+
+// quality factors of the two sections of a 4th order butterworth filter
+const BUTTERWORTH_Q: [f64; 2] = [0.541_196_100_146_197, 1.306_562_964_876_376_7];
+
+// second order section in transposed direct form II, coefficients after the
+// audio EQ cookbook of R. Bristow-Johnson
+#[derive(Debug, Clone)]
+pub struct Biquad {
+    b0: f64,
+    b1: f64,
+    b2: f64,
+    a1: f64,
+    a2: f64,
+    z1: f64,
+    z2: f64,
+}
+
+impl Biquad {
+    pub fn lowpass(freq: f64, q: f64, samplerate: f64) -> Self {
+        let (cos, alpha) = Biquad::omega(freq, q, samplerate);
+        Biquad::normalized([(1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0], [1.0 + alpha, -2.0 * cos, 1.0 - alpha])
+    }
+
+    pub fn highpass(freq: f64, q: f64, samplerate: f64) -> Self {
+        let (cos, alpha) = Biquad::omega(freq, q, samplerate);
+        Biquad::normalized([(1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0], [1.0 + alpha, -2.0 * cos, 1.0 - alpha])
+    }
+
+    // corner frequencies at or above half the samplerate are moved just below it
+    fn omega(freq: f64, q: f64, samplerate: f64) -> (f64, f64) {
+        let freq = freq.clamp(1e-3, 0.49 * samplerate);
+        let omega = 2.0 * PI * freq / samplerate;
+        (omega.cos(), omega.sin() / (2.0 * q))
+    }
+
+    fn normalized(b: [f64; 3], a: [f64; 3]) -> Self {
+        Self {
+            b0: b[0] / a[0],
+            b1: b[1] / a[0],
+            b2: b[2] / a[0],
+            a1: a[1] / a[0],
+            a2: a[2] / a[0],
+            z1: 0.0,
+            z2: 0.0,
+        }
+    }
+
+    pub fn run(&mut self, x: f64) -> f64 {
+        let y = self.b0 * x + self.z1;
+        self.z1 = self.b1 * x - self.a1 * y + self.z2;
+        self.z2 = self.b2 * x - self.a2 * y;
+        y
+    }
+
+    pub fn reset(&mut self) {
+        self.z1 = 0.0;
+        self.z2 = 0.0;
+    }
+}
+
+// 4th order butterworth edges from cascaded biquads, runs sample by sample and
+// keeps its state from block to block
+#[derive(Debug, Clone)]
+pub struct IirFilter {
+    sections: Vec<Biquad>,
+}
+
+impl IirFilter {
+    pub fn highpass(cutoff: f64, samplerate: f64) -> Self {
+        Self {
+            sections: BUTTERWORTH_Q.iter().map(|&q| Biquad::highpass(cutoff, q, samplerate)).collect(),
+        }
+    }
+
+    pub fn lowpass(cutoff: f64, samplerate: f64) -> Self {
+        Self {
+            sections: BUTTERWORTH_Q.iter().map(|&q| Biquad::lowpass(cutoff, q, samplerate)).collect(),
+        }
+    }
+
+    // high-pass at the low edge followed by a low-pass at the high edge
+    pub fn bandpass(low: f64, high: f64, samplerate: f64) -> Self {
+        let mut sections = IirFilter::highpass(low, samplerate).sections;
+        sections.extend(IirFilter::lowpass(high, samplerate).sections);
+        Self { sections }
+    }
+
+    pub fn run(&mut self, x: f64) -> f64 {
+        self.sections.iter_mut().fold(x, |x, section| section.run(x))
+    }
+}
+
+impl Processor for IirFilter {
+    // filters from a clean state without touching the state of the stream
+    fn process(&mut self, track: &AudioTrack) -> AudioTrack {
+        let mut filter = IirFilter {
+            sections: self.sections.clone(),
+        };
+        filter.reset();
+        filter.process_block(track)
+    }
+
+    fn process_block(&mut self, block: &AudioTrack) -> AudioTrack {
+        let volume: Vec<f64> = block.get_volume().into_iter().map(|x| self.run(x)).collect();
+        let mut block = block.clone();
+        block.update_volume(volume)
+    }
+
+    fn reset(&mut self) {
+        self.sections.iter_mut().for_each(|section| section.reset());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLERATE: f64 = 48000.0;
+
+    fn track(samples: impl Iterator<Item = f64>) -> AudioTrack {
+        AudioTrack::from_rate_track(SAMPLERATE, samples.enumerate().map(|(n, v)| (n as f64 / SAMPLERATE, v)).collect())
+    }
+
+    // gain in dB of a 1 s sine once the filter has settled
+    fn gain(filter: &IirFilter, freq: f64) -> f64 {
+        let sine = track((0..SAMPLERATE as usize).map(|n| (2.0 * PI * freq * n as f64 / SAMPLERATE).sin()));
+        let output = filter.clone().process_block(&sine).get_volume();
+        let rms = |values: &[f64]| (values.iter().map(|v| v * v).sum::<f64>() / values.len() as f64).sqrt();
+        let half = output.len() / 2;
+        20.0 * (rms(&output[half..]) / rms(&sine.get_volume()[half..])).log10()
+    }
+
+    #[test]
+    fn bandpass_keeps_the_clicks_and_drops_the_rest() {
+        let filter = IirFilter::bandpass(1000.0, 10000.0, SAMPLERATE);
+        for freq in [2000.0, 3000.0, 5000.0] {
+            let gain = gain(&filter, freq);
+            assert!(gain.abs() < 0.5, "{freq} Hz: {gain} dB in the passband");
+        }
+        for freq in [1000.0, 10000.0] {
+            let gain = gain(&filter, freq);
+            assert!((gain + 3.0).abs() < 0.5, "{freq} Hz: {gain} dB at the corner");
+        }
+        for (freq, max) in [(50.0, -80.0), (200.0, -50.0), (20000.0, -30.0)] {
+            let gain = gain(&filter, freq);
+            assert!(gain < max, "{freq} Hz: {gain} dB in the stopband");
+        }
+    }
+
+    #[test]
+    fn split_blocks_give_the_same_output() {
+        // a sweep and a pseudo random sequence so that every sample differs
+        let mut state: u64 = 1;
+        let signal = track((0..10000).map(|n| {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (2.0 * PI * 1e-4 * (n * n) as f64 / SAMPLERATE).sin() + 0.1 * ((state >> 11) as f64 / (1u64 << 53) as f64 - 0.5)
+        }));
+        let whole = IirFilter::bandpass(1000.0, 10000.0, SAMPLERATE).process_block(&signal).get_volume();
+
+        let mut filter = IirFilter::bandpass(1000.0, 10000.0, SAMPLERATE);
+        let mut split = Vec::new();
+        let mut start = 0;
+        for len in [1, 7, 480, 3, 4096].into_iter().cycle() {
+            if start >= signal.track.len() {
+                break;
+            }
+            let end = (start + len).min(signal.track.len());
+            let block = AudioTrack::from_rate_track(SAMPLERATE, signal.track[start..end].to_vec());
+            split.extend(filter.process_block(&block).get_volume());
+            start = end;
+        }
+        assert_eq!(split, whole);
+    }
+}
@@ -0,0 +1,90 @@
+use crate::signal::beats::BeatEvent;
+use std::collections::VecDeque;
+
+// results of the timegrapher measurements on the last processed frame
+#[derive(Debug, Clone, Default)]
+pub struct Measurement {
+    pub beats: Vec<BeatEvent>,
+    // beats of the whole session, oldest first and capped in length
+    pub trace: Vec<BeatEvent>,
+    // beat rate the measurements were made with
+    pub bph: Option<u32>,
+    // rate in s/d
+    pub rate: Option<f64>,
+    // rolling mean and standard deviation of the beat error in ms
+    pub beat_error: Option<f64>,
+    pub beat_error_std: Option<f64>,
+    // rolling mean of the balance amplitude in degrees
+    pub amplitude: Option<f64>,
+    // number of gaps from dropped samples in the frame, measurements skip such frames
+    pub gaps: usize,
+    // spectrum of the denoiser input when the chain has a denoiser
+    pub spectrum: Option<Spectrum>,
+    // speech probability of the denoiser in [0, 1], the estimate of the watch being heard
+    pub presence: Option<f64>,
+    // a noise profile is being learned, the watch should be off the microphone
+    pub learning_noise: bool,
+}
+
+// power spectrum and noise estimate on equally spaced bins from 0 Hz to half the samplerate
+#[derive(Debug, Clone, Default)]
+pub struct Spectrum {
+    pub samplerate: f64,
+    pub power: Vec<f64>,
+    pub noise: Vec<f64>,
+}
+
+impl Spectrum {
+    // centre frequency of a bin in Hz
+    pub fn frequency(&self, bin: usize) -> f64 {
+        match self.power.len() {
+            0 => 0.0,
+            len => bin as f64 * 0.5 * self.samplerate / len as f64,
+        }
+    }
+}
+
+// mean and standard deviation over the last `window` values
+#[derive(Debug, Clone)]
+pub struct RollingStats {
+    window: usize,
+    values: VecDeque<f64>,
+}
+
+impl RollingStats {
+    pub fn new(window: usize) -> Self {
+        Self {
+            window,
+            values: VecDeque::with_capacity(window),
+        }
+    }
+
+    pub fn push(&mut self, value: f64) {
+        if self.values.len() == self.window {
+            self.values.pop_front();
+        }
+        self.values.push_back(value);
+    }
+
+    pub fn extend(&mut self, values: &[f64]) {
+        values.iter().for_each(|&v| self.push(v));
+    }
+
+    pub fn clear(&mut self) {
+        self.values.clear();
+    }
+
+    pub fn mean(&self) -> Option<f64> {
+        match self.values.len() {
+            0 => None,
+            len => Some(self.values.iter().sum::<f64>() / len as f64),
+        }
+    }
+
+    pub fn std(&self) -> Option<f64> {
+        let mean = self.mean()?;
+        let len = self.values.len() as f64;
+        let var = self.values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / len;
+        Some(var.sqrt())
+    }
+}
@@ -1,4 +1,15 @@
 pub mod fft;
 pub mod utils;
 pub mod calculator;
-pub mod speexdsp;
\ No newline at end of file
+#[cfg(feature = "speexdsp")]
+pub mod speexdsp;
+pub mod spectral;
+pub mod filter;
+pub mod processor;
+pub mod beats;
+pub mod rate;
+pub mod measure;
+pub mod beat_error;
+pub mod amplitude;
+pub mod bph;
+pub mod analyzer;
\ No newline at end of file
@@ -0,0 +1,306 @@
+use crate::audio::track::AudioTrack;
+use crate::signal::{calculator::BitCalculator, fft, measure::Spectrum};
+use anyhow::{anyhow, Result};
+use core::fmt;
+use serde::Serialize;
+use std::str::FromStr;
+
+// one stage of the signal chain
+pub trait Processor {
+    // process a whole track
+    fn process(&mut self, track: &AudioTrack) -> AudioTrack;
+
+    // process the next block of a stream, stages with memory carry it over to the
+    // next block, stateless stages treat the block like a whole track
+    fn process_block(&mut self, block: &AudioTrack) -> AudioTrack {
+        self.process(block)
+    }
+
+    // forget everything carried over from previous blocks
+    fn reset(&mut self) {}
+
+    // delay in seconds the stage adds when processing blocks
+    fn latency(&self) -> f64 {
+        0.0
+    }
+
+    // probability in [0, 1] that the last block held a signal, for stages with a
+    // voice activity detector
+    fn presence(&self) -> Option<f64> {
+        None
+    }
+
+    // power spectrum and noise estimate of the last processed frame, for stages that track them
+    fn spectrum(&self) -> Option<Spectrum> {
+        None
+    }
+}
+
+// plain track functions such as utils::abs or a closure over utils::cutt_off
+impl<F> Processor for F
+where
+    F: FnMut(&AudioTrack) -> AudioTrack,
+{
+    fn process(&mut self, track: &AudioTrack) -> AudioTrack {
+        self(track)
+    }
+}
+
+impl Processor for BitCalculator {
+    fn process(&mut self, track: &AudioTrack) -> AudioTrack {
+        BitCalculator::new(track.clone()).run_calculator()
+    }
+}
+
+pub fn lowpass(cutoff_freq: f64) -> impl Processor {
+    move |track: &AudioTrack| {
+        let volume = fft::lowpass_filter(track.clone(), cutoff_freq);
+        track.clone().update_volume(volume)
+    }
+}
+
+// stages run one after another
+#[derive(Default)]
+pub struct Chain {
+    stages: Vec<Box<dyn Processor>>,
+}
+
+impl Chain {
+    pub fn new() -> Self {
+        Self { stages: Vec::new() }
+    }
+
+    pub fn with_stage(mut self, stage: Box<dyn Processor>) -> Self {
+        self.stages.push(stage);
+        self
+    }
+}
+
+impl Processor for Chain {
+    fn process(&mut self, track: &AudioTrack) -> AudioTrack {
+        self.stages
+            .iter_mut()
+            .fold(track.clone(), |track, stage| stage.process(&track))
+    }
+
+    fn process_block(&mut self, block: &AudioTrack) -> AudioTrack {
+        self.stages
+            .iter_mut()
+            .fold(block.clone(), |block, stage| stage.process_block(&block))
+    }
+
+    fn reset(&mut self) {
+        self.stages.iter_mut().for_each(|stage| stage.reset());
+    }
+
+    fn latency(&self) -> f64 {
+        self.stages.iter().map(|stage| stage.latency()).sum()
+    }
+
+    fn presence(&self) -> Option<f64> {
+        self.stages.iter().find_map(|stage| stage.presence())
+    }
+
+    fn spectrum(&self) -> Option<Spectrum> {
+        self.stages.iter().find_map(|stage| stage.spectrum())
+    }
+}
+
+// configurable description of a stage, written as `name` or `name(value)`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum Stage {
+    // speex denoiser and AGC with the levels from the settings, spectral subtraction
+    // in builds without the speexdsp feature
+    Denoise,
+    // spectral subtraction of signal::spectral, the noise suppression level is its floor
+    Spectral,
+    // beat envelope of BitCalculator
+    Envelope,
+    // utils::cutt_off at the cutoff from the settings
+    Cutoff,
+    Gain(f64),
+    RemoveMean,
+    Abs,
+    Diff,
+    // window in samples
+    SlidingMax(usize),
+    SlidingMean(usize),
+    // butterworth filters of signal::filter at the given corner frequencies in Hz
+    HighPass(f64),
+    LowPass(f64),
+    BandPass(f64, f64),
+    // fft::lowpass_filter at the given frequency in Hz
+    FftLowPass(f64),
+}
+
+impl Stage {
+    // the chain used before it became configurable
+    pub fn default_chain() -> Vec<Stage> {
+        vec![Stage::Denoise, Stage::Envelope, Stage::Cutoff]
+    }
+
+    // comma separated list of stages, e.g. "bandpass(1000, 10000), denoise, envelope, cutoff",
+    // commas within parentheses separate the values of a stage
+    pub fn parse_chain(chain: &str) -> Result<Vec<Stage>> {
+        let mut depth = 0;
+        let stages = chain
+            .split(|c: char| {
+                match c {
+                    '(' => depth += 1,
+                    ')' => depth -= 1,
+                    _ => {}
+                }
+                c == ',' && depth == 0
+            })
+            .map(|stage| stage.trim())
+            .filter(|stage| !stage.is_empty())
+            .map(Stage::from_str)
+            .collect::<Result<Vec<Stage>>>()?;
+
+        // the denoisers learn the noise over many blocks, on the window they would
+        // start over with every block
+        let (_, window_stages) = Stage::split_chain(&stages);
+        if let Some(stage) = window_stages.iter().find(|stage| matches!(stage, Stage::Denoise | Stage::Spectral)) {
+            return Err(anyhow!("Stage '{:}' has to come before '{:}'", stage, window_stages[0]));
+        }
+        Ok(stages)
+    }
+
+    // stages that keep their state from block to block and run on the stream,
+    // the others run on the whole analysis window
+    pub fn is_streaming(&self) -> bool {
+        matches!(
+            self,
+            Stage::Denoise | Stage::Spectral | Stage::HighPass(_) | Stage::LowPass(_) | Stage::BandPass(_, _)
+        )
+    }
+
+    // the leading streaming stages and the rest of the chain
+    pub fn split_chain(chain: &[Stage]) -> (&[Stage], &[Stage]) {
+        let split = chain.iter().position(|stage| !stage.is_streaming()).unwrap_or(chain.len());
+        chain.split_at(split)
+    }
+
+    pub fn format_chain(chain: &[Stage]) -> String {
+        chain.iter().map(|stage| stage.to_string()).collect::<Vec<String>>().join(", ")
+    }
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Stage::Denoise => write!(f, "denoise"),
+            Stage::Spectral => write!(f, "spectral"),
+            Stage::Envelope => write!(f, "envelope"),
+            Stage::Cutoff => write!(f, "cutoff"),
+            Stage::Gain(gain) => write!(f, "gain({:})", gain),
+            Stage::RemoveMean => write!(f, "remove_mean"),
+            Stage::Abs => write!(f, "abs"),
+            Stage::Diff => write!(f, "diff"),
+            Stage::SlidingMax(window) => write!(f, "sliding_max({:})", window),
+            Stage::SlidingMean(window) => write!(f, "sliding_mean({:})", window),
+            Stage::HighPass(freq) => write!(f, "highpass({:})", freq),
+            Stage::LowPass(freq) => write!(f, "lowpass({:})", freq),
+            Stage::BandPass(low, high) => write!(f, "bandpass({:}, {:})", low, high),
+            Stage::FftLowPass(freq) => write!(f, "fft_lowpass({:})", freq),
+        }
+    }
+}
+
+impl FromStr for Stage {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim().to_ascii_lowercase();
+        let (name, arg) = match s.split_once('(') {
+            Some((name, rest)) => {
+                let arg = rest
+                    .strip_suffix(')')
+                    .ok_or(anyhow!("Missing ')' in stage '{:}'", s))?;
+                (name.trim().to_string(), Some(arg.trim().to_string()))
+            }
+            None => (s.clone(), None),
+        };
+
+        let number = |arg: &Option<String>| -> Result<f64> {
+            let arg = arg.as_ref().ok_or(anyhow!("Stage '{:}' needs a value, e.g. {:}(1)", name, name))?;
+            arg.parse::<f64>()
+                .map_err(|_| anyhow!("Invalid value '{:}' for stage '{:}'", arg, name))
+        };
+        let frequency = |value: &str| -> Result<f64> {
+            match value.trim().parse::<f64>() {
+                Ok(freq) if freq > 0.0 => Ok(freq),
+                _ => Err(anyhow!("Invalid frequency '{:}' for stage '{:}'", value.trim(), name)),
+            }
+        };
+        let corner = |arg: &Option<String>| -> Result<f64> {
+            let arg = arg.as_ref().ok_or(anyhow!("Stage '{:}' needs a frequency, e.g. {:}(1000)", name, name))?;
+            frequency(arg)
+        };
+        let band = |arg: &Option<String>| -> Result<(f64, f64)> {
+            let arg = arg.as_ref().ok_or(anyhow!("Stage '{:}' needs two frequencies, e.g. {:}(1000, 10000)", name, name))?;
+            let (low, high) = arg
+                .split_once(',')
+                .ok_or(anyhow!("Stage '{:}' needs two frequencies, e.g. {:}(1000, 10000)", name, name))?;
+            match (frequency(low)?, frequency(high)?) {
+                (low, high) if low < high => Ok((low, high)),
+                _ => Err(anyhow!("Low frequency of stage '{:}' must be below the high one", name)),
+            }
+        };
+        let window = |arg: &Option<String>| -> Result<usize> {
+            match number(arg)? {
+                w if w >= 1.0 => Ok(w as usize),
+                _ => Err(anyhow!("Window of stage '{:}' must be at least one sample", name)),
+            }
+        };
+
+        let stage = match name.as_str() {
+            "denoise" => Stage::Denoise,
+            "spectral" => Stage::Spectral,
+            "envelope" => Stage::Envelope,
+            "cutoff" => Stage::Cutoff,
+            "gain" => Stage::Gain(number(&arg)?),
+            "remove_mean" => Stage::RemoveMean,
+            "abs" => Stage::Abs,
+            "diff" => Stage::Diff,
+            "sliding_max" => Stage::SlidingMax(window(&arg)?),
+            "sliding_mean" => Stage::SlidingMean(window(&arg)?),
+            "highpass" => Stage::HighPass(corner(&arg)?),
+            "lowpass" => Stage::LowPass(corner(&arg)?),
+            "bandpass" => {
+                let (low, high) = band(&arg)?;
+                Stage::BandPass(low, high)
+            }
+            "fft_lowpass" => Stage::FftLowPass(number(&arg)?),
+            _ => return Err(anyhow!("Unknown stage '{:}'", name)),
+        };
+
+        let takes_value = matches!(
+            stage,
+            Stage::Gain(_)
+                | Stage::SlidingMax(_)
+                | Stage::SlidingMean(_)
+                | Stage::HighPass(_)
+                | Stage::LowPass(_)
+                | Stage::BandPass(_, _)
+                | Stage::FftLowPass(_)
+        );
+        if arg.is_some() && !takes_value {
+            return Err(anyhow!("Stage '{:}' takes no value", name));
+        }
+        Ok(stage)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn denoisers_only_run_on_the_stream() {
+        assert!(Stage::parse_chain("highpass(500), denoise, envelope, lowpass(100)").is_ok());
+        assert!(Stage::parse_chain("spectral, envelope").is_ok());
+        assert!(Stage::parse_chain("envelope, denoise").is_err());
+        assert!(Stage::parse_chain("bandpass(1000, 10000), abs, spectral").is_err());
+    }
+}
@@ -0,0 +1,69 @@
+use crate::signal::beats::BeatEvent;
+
+pub const SECONDS_PER_DAY: f64 = 86400.0;
+
+// nominal time between two beats in seconds
+pub fn beat_period(bph: u32) -> f64 {
+    3600.0 / bph as f64
+}
+
+pub struct RateCalculator {
+    beats: Vec<BeatEvent>,
+    bph: u32,
+}
+
+impl RateCalculator {
+    pub fn new(beats: Vec<BeatEvent>, bph: u32) -> Self {
+        Self { beats, bph }
+    }
+
+    // least squares slope of beat time against beat index, ticks and tocks get their own
+    // intercept so that the beat error does not tilt the fit on short windows
+    fn regress(points: &[(f64, f64)]) -> Option<f64> {
+        let (mut sxx, mut sxy) = (0.0, 0.0);
+        for parity in [0.0, 1.0] {
+            let group: Vec<&(f64, f64)> = points.iter().filter(|(x, _)| x.rem_euclid(2.0) == parity).collect();
+            if group.is_empty() {
+                continue;
+            }
+            let n = group.len() as f64;
+            let mean_x = group.iter().map(|(x, _)| x).sum::<f64>() / n;
+            let mean_y = group.iter().map(|(_, y)| y).sum::<f64>() / n;
+
+            sxx += group.iter().map(|(x, _)| (x - mean_x).powi(2)).sum::<f64>();
+            sxy += group.iter().map(|(x, y)| (x - mean_x) * (y - mean_y)).sum::<f64>();
+        }
+
+        if sxx <= 0.0 {
+            return None;
+        }
+        Some(sxy / sxx)
+    }
+
+    // daily rate in s/d, positive when the watch is gaining, fitted on the confident beats
+    pub fn run_calculator(&self) -> Option<f64> {
+        let beats: Vec<&BeatEvent> = self.beats.iter().filter(|b| b.is_confident()).collect();
+        if self.bph == 0 || beats.len() < 3 {
+            return None;
+        }
+        let period = beat_period(self.bph);
+        let start = beats[0].time;
+
+        // beats are numbered by adding up the periods between neighbours, so that missed
+        // beats keep their slot and the drift of a long session never moves a beat over
+        let mut index: f64 = 0.0;
+        let mut points: Vec<(f64, f64)> = Vec::with_capacity(beats.len());
+        for (n, b) in beats.iter().enumerate() {
+            if n > 0 {
+                index += ((b.time - beats[n - 1].time) / period).round().max(1.0);
+            }
+            points.push((index, b.time - start));
+        }
+
+        let slope = RateCalculator::regress(&points)?;
+        if slope <= 0.0 {
+            return None;
+        }
+        Some(SECONDS_PER_DAY * (period / slope - 1.0))
+    }
+}
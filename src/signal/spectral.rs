@@ -0,0 +1,277 @@
+use crate::audio::track::AudioTrack;
+use crate::signal::measure::Spectrum;
+use crate::signal::processor::Processor;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use rustfft::{num_complex::Complex, Fft, FftPlanner};
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+// the noise power is subtracted this many times over to make up for the spread of the estimate
+const OVERSUBTRACTION: f64 = 2.0;
+// how fast the tracked noise floor may rise in dB per second when no profile was learned
+const NOISE_RISE: f64 = 3.0;
+// time constant in seconds of the smoothed power the noise floor is tracked on, well
+// below the beat period so that the power falls back to the noise between ticks
+const SMOOTHING_TIME: f64 = 0.025;
+// the tracked minimum of the smoothed power lies about this far below the mean noise power
+const MINIMUM_BIAS: f64 = 2.0;
+
+// mean noise power per bin of a recording without the watch, only valid for the
+// samplerate and hop it was learned with
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NoiseProfile {
+    pub samplerate: f64,
+    pub power: Vec<f64>,
+}
+
+impl NoiseProfile {
+    pub fn fits(&self, hop: usize, samplerate: f64) -> bool {
+        self.samplerate == samplerate && self.power.len() == hop.max(1) + 1
+    }
+}
+
+// spectral subtraction on half overlapping frames of two hops: every frame is windowed,
+// the noise power is subtracted bin by bin and the frames are added back together.
+// The noise comes from a profile learned on a silent recording, without one it follows
+// the minimum of the recent frames, ticks are short enough that every bin falls back to
+// the noise floor between them
+pub struct SpectralDenoiser {
+    hop: usize,
+    samplerate: f64,
+    fft: Arc<dyn Fft<f64>>,
+    ifft: Arc<dyn Fft<f64>>,
+    // square root of a periodic hann window, applied before and after the fft so
+    // that the overlapping frames add up to the input
+    window: Vec<f64>,
+    // lowest amplitude gain of a bin
+    floor: f64,
+    profile: Option<NoiseProfile>,
+    // tracked noise floor, smoothed power and power of the last frame on the bins
+    // from 0 Hz to half the samplerate
+    noise: Vec<f64>,
+    smoothed: Vec<f64>,
+    power: Vec<f64>,
+    // input of the previous hop and the second half of the previous output frame
+    previous: Vec<f64>,
+    overlap: Vec<f64>,
+    pending: Vec<f64>,
+    output: VecDeque<f64>,
+}
+
+impl SpectralDenoiser {
+    // hop in samples, frames are two hops long
+    pub fn new(hop: usize, samplerate: f64) -> Self {
+        let hop = hop.max(1);
+        let mut planner = FftPlanner::new();
+        let window = (0..2 * hop)
+            .map(|i| (0.5 - 0.5 * (PI * i as f64 / hop as f64).cos()).sqrt())
+            .collect();
+        Self {
+            hop,
+            samplerate,
+            fft: planner.plan_fft_forward(2 * hop),
+            ifft: planner.plan_fft_inverse(2 * hop),
+            window,
+            floor: 0.0,
+            profile: None,
+            noise: Vec::new(),
+            smoothed: Vec::new(),
+            power: vec![0.0; hop + 1],
+            previous: vec![0.0; hop],
+            overlap: vec![0.0; hop],
+            pending: Vec::new(),
+            output: VecDeque::new(),
+        }
+    }
+
+    // maximum attenuation in dB, zero or negative
+    pub fn with_suppression(mut self, db: f64) -> Self {
+        self.floor = 10f64.powf(db.min(0.0) / 20.0);
+        self
+    }
+
+    // a profile learned with the same samplerate and hop
+    pub fn with_profile(mut self, profile: NoiseProfile) -> Result<Self> {
+        if !profile.fits(self.hop, self.samplerate) {
+            return Err(anyhow!(
+                "Noise profile of {:} bins at {:} Hz does not fit the denoiser with {:} bins at {:} Hz",
+                profile.power.len(),
+                profile.samplerate,
+                self.bins(),
+                self.samplerate
+            ));
+        }
+        self.profile = Some(profile);
+        Ok(self)
+    }
+
+    // mean power of the frames of a recording without the watch
+    pub fn learn(&mut self, silence: &AudioTrack) -> Result<()> {
+        let volume = silence.get_volume();
+        let frames: Vec<Vec<f64>> = volume
+            .windows(2 * self.hop)
+            .step_by(self.hop)
+            .map(|frame| self.frame_power(frame))
+            .collect();
+        if frames.is_empty() {
+            return Err(anyhow!(
+                "At least {:.3} s of silence are needed to learn the noise",
+                2.0 * self.hop as f64 / self.samplerate
+            ));
+        }
+        let mut power = vec![0.0; self.bins()];
+        for frame in frames.iter() {
+            power.iter_mut().zip(frame).for_each(|(p, v)| *p += v / frames.len() as f64);
+        }
+        self.profile = Some(NoiseProfile {
+            samplerate: self.samplerate,
+            power,
+        });
+        Ok(())
+    }
+
+    pub fn profile(&self) -> Option<&NoiseProfile> {
+        self.profile.as_ref()
+    }
+
+    // number of bins from 0 Hz to half the samplerate
+    pub fn bins(&self) -> usize {
+        self.hop + 1
+    }
+
+    fn frame_spectrum(&self, frame: &[f64]) -> Vec<Complex<f64>> {
+        let mut buffer: Vec<Complex<f64>> = frame
+            .iter()
+            .zip(self.window.iter())
+            .map(|(&v, &w)| Complex::new(v * w, 0.0))
+            .collect();
+        self.fft.process(&mut buffer);
+        buffer
+    }
+
+    fn frame_power(&self, frame: &[f64]) -> Vec<f64> {
+        self.frame_spectrum(frame)[..self.bins()].iter().map(|c| c.norm_sqr()).collect()
+    }
+
+    // the noise floor follows drops of the smoothed power at once and rises by at most
+    // NOISE_RISE, the smoothing keeps it from sinking into the dips of single frames
+    fn update_noise(&mut self) {
+        if self.noise.len() != self.power.len() {
+            self.smoothed = self.power.clone();
+            self.noise = self.power.clone();
+            return;
+        }
+        let weight = (self.hop as f64 / self.samplerate / SMOOTHING_TIME).min(1.0);
+        self.smoothed
+            .iter_mut()
+            .zip(self.power.iter())
+            .for_each(|(smoothed, &power)| *smoothed += weight * (power - *smoothed));
+        let rise = 10f64.powf(NOISE_RISE / 10.0 * self.hop as f64 / self.samplerate);
+        self.noise
+            .iter_mut()
+            .zip(self.smoothed.iter())
+            .for_each(|(noise, &smoothed)| *noise = smoothed.min(*noise * rise));
+    }
+
+    // the learned noise, or the tracked one once the bias of the minimum is made up for
+    fn noise_power(&self) -> Vec<f64> {
+        match &self.profile {
+            Some(profile) => profile.power.clone(),
+            None => self.noise.iter().map(|v| v * MINIMUM_BIAS).collect(),
+        }
+    }
+
+    // takes the next hop of input and returns the output of the previous hop
+    fn run_frame(&mut self, samples: &[f64]) -> Vec<f64> {
+        let frame: Vec<f64> = self.previous.iter().chain(samples.iter()).cloned().collect();
+        let mut buffer = self.frame_spectrum(&frame);
+        self.power = buffer[..self.bins()].iter().map(|c| c.norm_sqr()).collect();
+        self.update_noise();
+
+        let noise = self.noise_power();
+        let size = buffer.len();
+        for (k, value) in buffer.iter_mut().enumerate() {
+            // the upper half mirrors the lower one
+            let bin = if k < self.bins() { k } else { size - k };
+            let ratio = noise[bin] / self.power[bin].max(f64::MIN_POSITIVE);
+            let gain = (1.0 - OVERSUBTRACTION * ratio).max(self.floor * self.floor).sqrt();
+            *value *= gain;
+        }
+        self.ifft.process(&mut buffer);
+
+        let frame: Vec<f64> = buffer
+            .iter()
+            .zip(self.window.iter())
+            .map(|(c, &w)| c.re * w / size as f64)
+            .collect();
+        let output = self.overlap.iter().zip(frame[..self.hop].iter()).map(|(a, b)| a + b).collect();
+        self.overlap = frame[self.hop..].to_vec();
+        self.previous = samples.to_vec();
+        output
+    }
+}
+
+impl Processor for SpectralDenoiser {
+    // runs like a stream from a clean state, the learned profile is kept
+    fn process(&mut self, track: &AudioTrack) -> AudioTrack {
+        self.reset();
+        let mut samples = track.get_volume();
+        let len = samples.len();
+        samples.resize(len + 2 * self.hop, 0.0);
+        let hop = self.hop;
+        let mut volume = Vec::with_capacity(samples.len());
+        for chunk in samples.chunks(hop) {
+            let mut chunk = chunk.to_vec();
+            chunk.resize(hop, 0.0);
+            volume.extend(self.run_frame(&chunk));
+        }
+        self.reset();
+        let mut track = track.clone();
+        track.update_volume(volume[hop..hop + len].to_vec())
+    }
+
+    // the output lags by two hops, one to fill a hop and one for the overlap
+    fn process_block(&mut self, block: &AudioTrack) -> AudioTrack {
+        if self.output.is_empty() && self.pending.is_empty() {
+            self.output.extend(std::iter::repeat_n(0.0, self.hop));
+        }
+        for value in block.get_volume() {
+            self.pending.push(value);
+            if self.pending.len() == self.hop {
+                let samples = std::mem::take(&mut self.pending);
+                let frame = self.run_frame(&samples);
+                self.output.extend(frame);
+            }
+        }
+        let volume: Vec<f64> = self.output.drain(..block.track.len()).collect();
+        let mut block = block.clone();
+        block.update_volume(volume)
+    }
+
+    fn reset(&mut self) {
+        self.noise.clear();
+        self.smoothed.clear();
+        self.power = vec![0.0; self.bins()];
+        self.previous = vec![0.0; self.hop];
+        self.overlap = vec![0.0; self.hop];
+        self.pending.clear();
+        self.output.clear();
+    }
+
+    fn latency(&self) -> f64 {
+        2.0 * self.hop as f64 / self.samplerate
+    }
+
+    // on the scale of 16 bit samples like the speex spectrum
+    fn spectrum(&self) -> Option<Spectrum> {
+        let scale = (i16::MAX as f64).powi(2);
+        let noise = self.noise_power();
+        Some(Spectrum {
+            samplerate: self.samplerate,
+            power: self.power[..self.hop].iter().map(|v| v * scale).collect(),
+            noise: noise.iter().take(self.hop).map(|v| v * scale).collect(),
+        })
+    }
+}
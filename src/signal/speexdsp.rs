@@ -1,11 +1,17 @@
+use crate::audio::track::AudioTrack;
+use crate::signal::measure::Spectrum;
+use crate::signal::processor::Processor;
+use anyhow::{anyhow, Result};
+use std::collections::VecDeque;
+
 mod ffi {
-    use libc::{c_int, c_void, c_float};
+    use libc::{c_int, c_short, c_void};
 
     extern "C" {
         // Define the necessary functions from the SpeexDSP library
         pub fn speex_preprocess_state_init(frame_size: c_int, sampling_rate: c_int) -> *mut c_void;
         pub fn speex_preprocess_state_destroy(st: *mut c_void);
-        pub fn speex_preprocess_run(st: *mut c_void, x: *mut c_float) -> c_int;
+        pub fn speex_preprocess_run(st: *mut c_void, x: *mut c_short) -> c_int;
         pub fn speex_preprocess_ctl(st: *mut c_void, request: c_int, ptr: *mut c_void) -> c_int;
 
         // Add other necessary functions and constants
@@ -48,7 +54,7 @@ pub enum SetControll {
     // Set maximal gain in dB (int32)
     AgcMaxGain = 30,
     // Set preprocessor Automatic Gain Control level (int32)
-    AgcTarget = 47,
+    AgcTarget = 46,
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -87,9 +93,19 @@ pub enum GetControll {
     AgcTarget = 47,
 }
 
-#[derive(Debug, Clone)]
+// owns the speex state, which is never null and freed on drop, so the denoiser
+// can not be cloned
+#[derive(Debug)]
 pub struct Denoiser {
     state: *mut libc::c_void,
+    frame_size: usize,
+    samplerate: f64,
+    // samples waiting for a full frame and processed samples waiting to be
+    // handed out when the denoiser runs on a stream of blocks
+    pending: Vec<f64>,
+    output: VecDeque<f64>,
+    // highest speech probability of the frames of the last block
+    presence: f64,
 }
 
 // Speex is thread unsafe so no Send !!
@@ -98,35 +114,304 @@ pub struct Denoiser {
 
 impl Denoiser {
     // Initialize the denoiser
-    pub fn new(frame_size: i32, sampling_rate: i32) -> Self {
-        unsafe {
-            let state = ffi::speex_preprocess_state_init(frame_size, sampling_rate);
-            Denoiser { state }
+    pub fn new(frame_size: i32, sampling_rate: i32) -> Result<Self> {
+        if frame_size <= 0 || sampling_rate <= 0 {
+            return Err(anyhow!(
+                "Speex preprocessor needs a positive frame size and samplerate, got {:} samples at {:} Hz",
+                frame_size,
+                sampling_rate
+            ));
+        }
+        let state = unsafe { ffi::speex_preprocess_state_init(frame_size, sampling_rate) };
+        if state.is_null() {
+            return Err(anyhow!(
+                "Unable to initialize the speex preprocessor for {:} samples at {:} Hz",
+                frame_size,
+                sampling_rate
+            ));
         }
+        Ok(Denoiser {
+            state,
+            frame_size: frame_size as usize,
+            samplerate: sampling_rate as f64,
+            pending: Vec::new(),
+            output: VecDeque::new(),
+            presence: 0.0,
+        })
     }
 
-    // Run the denoiser
-    pub fn process(&self, frame: &mut [f32]) -> bool {
-        unsafe {
-            ffi::speex_preprocess_run(self.state, frame.as_mut_ptr()) != 0
+    // Run the denoiser on one frame of 16 bit samples, shorter frames are padded with
+    // silence and samples beyond the frame size are left as they are
+    pub fn process(&mut self, frame: &mut [i16]) -> bool {
+        let len = frame.len().min(self.frame_size);
+        let mut padded = frame[..len].to_vec();
+        padded.resize(self.frame_size, 0);
+        let voice = unsafe { ffi::speex_preprocess_run(self.state, padded.as_mut_ptr()) != 0 };
+        frame[..len].copy_from_slice(&padded[..len]);
+        voice
+    }
+
+    pub fn set_denoise(&mut self, on: bool) -> Result<()> {
+        self.set_int(SetControll::Denoise, on as i32)
+    }
+
+    pub fn denoise(&self) -> Result<bool> {
+        Ok(self.get_int(GetControll::Denoise)? != 0)
+    }
+
+    pub fn set_agc(&mut self, on: bool) -> Result<()> {
+        self.set_int(SetControll::Agc, on as i32)
+    }
+
+    pub fn agc(&self) -> Result<bool> {
+        Ok(self.get_int(GetControll::Agc)? != 0)
+    }
+
+    // with the VAD on, frames without speech are muted and `process` reports the decision
+    pub fn set_vad(&mut self, on: bool) -> Result<()> {
+        self.set_int(SetControll::Vad, on as i32)
+    }
+
+    pub fn vad(&self) -> Result<bool> {
+        Ok(self.get_int(GetControll::Vad)? != 0)
+    }
+
+    pub fn set_dereverb(&mut self, on: bool) -> Result<()> {
+        self.set_int(SetControll::Dereverb, on as i32)
+    }
+
+    pub fn dereverb(&self) -> Result<bool> {
+        Ok(self.get_int(GetControll::Dereverb)? != 0)
+    }
+
+    // maximum attenuation of the noise in dB, zero or negative
+    pub fn set_noise_suppress(&mut self, db: i32) -> Result<()> {
+        if db > 0 {
+            return Err(anyhow!("Noise suppression must be zero or negative, got {:} dB", db));
         }
+        self.set_int(SetControll::NoiseSuppress, db)
     }
 
-    pub fn set_ctl(self, request: SetControll, value: i32) -> Self {
-        let mut value = value;
-        unsafe {
-            ffi::speex_preprocess_ctl(self.state, request as i32, &mut value as *mut _ as *mut libc::c_void);
+    pub fn noise_suppress(&self) -> Result<i32> {
+        self.get_int(GetControll::NoiseSuppress)
+    }
+
+    // loudness the AGC aims for, on the scale of 16 bit samples
+    pub fn set_agc_level(&mut self, level: f32) -> Result<()> {
+        if !(1.0..=32768.0).contains(&level) {
+            return Err(anyhow!("AGC level must be between 1 and 32768, got {:}", level));
+        }
+        let mut level = level;
+        self.ctl(SetControll::AgcLevel as i32, &mut level as *mut _ as *mut libc::c_void)
+    }
+
+    pub fn agc_level(&self) -> Result<f32> {
+        let mut level: f32 = 0.0;
+        self.ctl(GetControll::AgcLevel as i32, &mut level as *mut _ as *mut libc::c_void)?;
+        Ok(level)
+    }
+
+    // loudness the AGC aims for as an integer, the same level as set_agc_level
+    pub fn set_agc_target(&mut self, level: i32) -> Result<()> {
+        if !(1..=32768).contains(&level) {
+            return Err(anyhow!("AGC target must be between 1 and 32768, got {:}", level));
+        }
+        self.set_int(SetControll::AgcTarget, level)
+    }
+
+    pub fn agc_target(&self) -> Result<i32> {
+        self.get_int(GetControll::AgcTarget)
+    }
+
+    // fastest rise of the AGC gain in dB per second, zero or positive
+    pub fn set_agc_increment(&mut self, db_per_second: i32) -> Result<()> {
+        if db_per_second < 0 {
+            return Err(anyhow!("AGC increment must be zero or positive, got {:} dB/s", db_per_second));
+        }
+        self.set_int(SetControll::AgcIncrement, db_per_second)
+    }
+
+    pub fn agc_increment(&self) -> Result<i32> {
+        self.get_int(GetControll::AgcIncrement)
+    }
+
+    // fastest fall of the AGC gain in dB per second, zero or negative
+    pub fn set_agc_decrement(&mut self, db_per_second: i32) -> Result<()> {
+        if db_per_second > 0 {
+            return Err(anyhow!("AGC decrement must be zero or negative, got {:} dB/s", db_per_second));
+        }
+        self.set_int(SetControll::AgcDecrement, db_per_second)
+    }
+
+    pub fn agc_decrement(&self) -> Result<i32> {
+        self.get_int(GetControll::AgcDecrement)
+    }
+
+    // highest gain the AGC may apply in dB, zero or positive
+    pub fn set_agc_max_gain(&mut self, db: i32) -> Result<()> {
+        if db < 0 {
+            return Err(anyhow!("AGC maximum gain must be zero or positive, got {:} dB", db));
+        }
+        self.set_int(SetControll::AgcMaxGain, db)
+    }
+
+    pub fn agc_max_gain(&self) -> Result<i32> {
+        self.get_int(GetControll::AgcMaxGain)
+    }
+
+    // gain the AGC applied to the last frame in percent
+    pub fn agc_gain(&self) -> Result<i32> {
+        self.get_int(GetControll::AgcGain)
+    }
+
+    // loudness of the last frame as the AGC measured it
+    pub fn agc_loudness(&self) -> Result<i32> {
+        self.get_int(GetControll::AgcLoudness)
+    }
+
+    // speech probability in percent for the VAD to go from silence to speech
+    pub fn set_prob_start(&mut self, percent: u8) -> Result<()> {
+        self.set_int(SetControll::ProbStart, percent_value(percent)?)
+    }
+
+    pub fn prob_start(&self) -> Result<u8> {
+        Ok(self.get_int(GetControll::ProbStart)?.clamp(0, 100) as u8)
+    }
+
+    // speech probability in percent for the VAD to stay in speech
+    pub fn set_prob_continue(&mut self, percent: u8) -> Result<()> {
+        self.set_int(SetControll::ProbContinue, percent_value(percent)?)
+    }
+
+    pub fn prob_continue(&self) -> Result<u8> {
+        Ok(self.get_int(GetControll::ProbContinue)?.clamp(0, 100) as u8)
+    }
+
+    // speech probability of the last frame in percent, the "watch present" estimate
+    pub fn speech_probability(&self) -> Result<u8> {
+        Ok(self.get_int(GetControll::Prob)?.clamp(0, 100) as u8)
+    }
+
+    // power spectrum of the last frame, squared magnitudes from 0 Hz up to half the samplerate
+    pub fn psd(&self) -> Vec<i32> {
+        self.get_array(GetControll::PsdSize, GetControll::Psd)
+    }
+
+    // noise estimate on the same bins as the power spectrum
+    pub fn noise_psd(&self) -> Vec<i32> {
+        self.get_array(GetControll::NoisePsdSize, GetControll::NoisePsd)
+    }
+}
+
+fn percent_value(percent: u8) -> Result<i32> {
+    match percent {
+        0..=100 => Ok(percent as i32),
+        _ => Err(anyhow!("Probability must be between 0 and 100 %, got {:}", percent)),
+    }
+}
+
+impl Denoiser {
+    // speex answers unknown requests with -1
+    fn ctl(&self, request: i32, ptr: *mut libc::c_void) -> Result<()> {
+        match unsafe { ffi::speex_preprocess_ctl(self.state, request, ptr) } {
+            0 => Ok(()),
+            code => Err(anyhow!("Speex preprocessor rejected request {:} ({:})", request, code)),
         }
-        self
     }
 
-    pub fn get_ctl(&self, request: GetControll, value: i32) -> i32 {
+    fn set_int(&mut self, request: SetControll, value: i32) -> Result<()> {
         let mut value = value;
-        unsafe {
-            ffi::speex_preprocess_ctl(self.state, request as i32, &mut value as *mut _ as *mut libc::c_void)
+        self.ctl(request as i32, &mut value as *mut _ as *mut libc::c_void)
+            .map_err(|err| anyhow!("Unable to set {:?} to {:}: {:}", request, value, err))
+    }
+
+    fn get_int(&self, request: GetControll) -> Result<i32> {
+        let mut value: i32 = 0;
+        self.ctl(request as i32, &mut value as *mut _ as *mut libc::c_void)
+            .map_err(|err| anyhow!("Unable to get {:?}: {:}", request, err))?;
+        Ok(value)
+    }
+
+    // the array controls write `size` values to the pointer, the size is asked first
+    fn get_array(&self, size: GetControll, request: GetControll) -> Vec<i32> {
+        let len = self.get_int(size).unwrap_or(0);
+        let mut values = vec![0i32; len.max(0) as usize];
+        if !values.is_empty() && self.ctl(request as i32, values.as_mut_ptr() as *mut libc::c_void).is_err() {
+            values.clear();
+        }
+        values
+    }
+
+    fn update_presence(&mut self) {
+        let probability = self.speech_probability().unwrap_or(0) as f64 / 100.0;
+        self.presence = self.presence.max(probability);
+    }
+
+    // run one frame of samples in [-1, 1]
+    fn run_frame(&mut self, samples: &[f64]) -> Vec<f64> {
+        let mut frame: Vec<i16> = samples
+            .iter()
+            .map(|&v| (v * i16::MAX as f64).round().clamp(i16::MIN as f64, i16::MAX as f64) as i16)
+            .collect();
+        self.process(&mut frame);
+        frame.iter().map(|&v| v as f64 / i16::MAX as f64).collect()
+    }
+}
+
+impl Processor for Denoiser {
+    fn process(&mut self, track: &AudioTrack) -> AudioTrack {
+        self.presence = 0.0;
+        let mut volume = Vec::with_capacity(track.track.len());
+        for chunk in track.get_volume().chunks(self.frame_size) {
+            volume.extend(self.run_frame(chunk));
+            self.update_presence();
         }
+        let mut track = track.clone();
+        track.update_volume(volume)
+    }
+
+    // blocks are cut into frames, the output lags by one frame
+    fn process_block(&mut self, block: &AudioTrack) -> AudioTrack {
+        if self.output.is_empty() && self.pending.is_empty() {
+            self.output.extend(std::iter::repeat_n(0.0, self.frame_size));
+        }
+        self.presence = 0.0;
+        for value in block.get_volume() {
+            self.pending.push(value);
+            if self.pending.len() == self.frame_size {
+                let samples = std::mem::take(&mut self.pending);
+                let frame = self.run_frame(&samples);
+                self.output.extend(frame);
+                self.update_presence();
+            }
+        }
+        let volume: Vec<f64> = self.output.drain(..block.track.len()).collect();
+        let mut block = block.clone();
+        block.update_volume(volume)
+    }
+
+    fn reset(&mut self) {
+        self.pending.clear();
+        self.output.clear();
+        self.presence = 0.0;
+    }
+
+    fn latency(&self) -> f64 {
+        self.frame_size as f64 / self.samplerate
+    }
+
+    fn presence(&self) -> Option<f64> {
+        Some(self.presence)
+    }
+
+    fn spectrum(&self) -> Option<Spectrum> {
+        let to_f64 = |values: Vec<i32>| values.into_iter().map(|v| v as f64).collect();
+        Some(Spectrum {
+            samplerate: self.samplerate,
+            power: to_f64(self.psd()),
+            noise: to_f64(self.noise_psd()),
+        })
     }
-    
 }
 
 impl Drop for Denoiser {
@@ -192,4 +192,15 @@ pub fn abs(track: &AudioTrack) -> AudioTrack {
         .zip(vol.iter().cloned())
         .collect::<Vec<(f64, f64)>>();
     AudioTrack::from_rate_track(rate, track)
+}
+
+// time ranges where samples are missing, detected as steps of more than 1.5 sample periods
+pub fn find_gaps(track: &AudioTrack) -> Vec<(f64, f64)> {
+    let rate = track.get_sample_rate();
+    let time = track.get_time();
+
+    time.windows(2)
+        .filter(|w| (w[1] - w[0]) * rate > 1.5)
+        .map(|w| (w[0], w[1]))
+        .collect()
 }
\ No newline at end of file
@@ -1,16 +1,33 @@
-use crate::audio::io::{AudioStreamBuilder, Connector};
+use crate::audio::io::{self as audioio, AudioStreamBuilder, Channel, Connector, InputConfig, Transport};
+use crate::audio::record::Recorder;
 use crate::audio::track::AudioTrack;
+use crate::audio::wav::WavStreamBuilder;
+use crate::signal::analyzer::AnalyzerSettings;
+use crate::signal::beats::{BeatEvent, Polarity};
+use crate::signal::bph::STANDARD_BPH;
+use crate::signal::measure::{Measurement, Spectrum};
+use crate::signal::processor::Stage;
+use crate::signal::spectral::NoiseProfile;
+use crate::signal::rate::beat_period;
+use crate::ui::config::{self, SavedState};
 use crate::ui::extras;
 use crate::ui::defs::*;
 use crate::ui::executor::{spawn_executor, ExecutorCTL};
 
 
-use eframe::egui::{emath::Vec2b, Align, ComboBox, Layout, Style, Visuals};
+use eframe::egui::{emath::Vec2b, Align, Color32, ComboBox, Layout, Style, Visuals};
 use eframe::{egui, App};
-use egui_plot::{Line, Plot, PlotBounds, PlotPoints};
+use egui_plot::{Legend, Line, Plot, PlotBounds, PlotPoints, Points};
+use anyhow::{anyhow, Result};
 use log::{info, warn, error};
-use std::sync::Arc;
-use tokio::{sync::Mutex, task::JoinHandle};
+use std::collections::BTreeMap;
+use std::fmt::{Debug, Display};
+use std::sync::{atomic::{AtomicU64, Ordering}, mpsc as std_mpsc, Arc};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::{sync::{watch, Mutex}, task::JoinHandle};
+
+// seconds recorded for a noise profile
+const NOISE_LEARN_TIME: f64 = 3.0;
 
 #[derive(PartialEq)]
 pub enum ShowData {
@@ -18,50 +35,341 @@ pub enum ShowData {
     Processed,
 }
 
+#[derive(PartialEq)]
+pub enum AudioSource {
+    Device,
+    File,
+}
+
+#[derive(PartialEq)]
+pub enum PlotMode {
+    Waveform,
+    Trace,
+    Spectrum,
+}
+
+// bins of a squared magnitude spectrum in dB against their frequency in Hz
+fn spectrum_points(spectrum: &Spectrum, values: &[f64]) -> Vec<[f64; 2]> {
+    values
+        .iter()
+        .enumerate()
+        .map(|(bin, &v)| [spectrum.frequency(bin), 10.0 * v.max(1.0).log10()])
+        .collect()
+}
+
+// paper strip points: beat number against the offset from the nominal beat grid in ms,
+// split into ticks and tocks; both only depend on the beat itself, so the strip does not
+// move when the oldest beats leave the trace
+fn trace_points(trace: &[BeatEvent], bph: u32) -> (Vec<[f64; 2]>, Vec<[f64; 2]>) {
+    let (mut ticks, mut tocks) = (Vec::new(), Vec::new());
+    if bph == 0 {
+        return (ticks, tocks);
+    }
+    let period = beat_period(bph);
+
+    for beat in trace.iter() {
+        let offset = 1000.0 * ((beat.time + 0.5 * period).rem_euclid(period) - 0.5 * period);
+        let point = [beat.index as f64, offset];
+        match beat.polarity {
+            Polarity::Tick => ticks.push(point),
+            Polarity::Tock => tocks.push(point),
+        }
+    }
+    (ticks, tocks)
+}
+
+// text field of a validated setting followed by its unit and, while the text is
+// invalid, the error; the text is reformatted once the field loses focus
+fn setting_field<T>(ui: &mut egui::Ui, setting: &mut Setting<T>, hint: &str, format: impl Fn(&T) -> String)
+where
+    T: ParseFilter + Debug + Clone + PartialOrd + Display,
+{
+    ui.horizontal(|ui| {
+        let mut text = setting.edit_text(format);
+        let response = ui.add(egui::TextEdit::singleline(&mut text).hint_text(hint).desired_width(60.0));
+        if response.changed() {
+            setting.parse(text);
+        }
+        if response.lost_focus() {
+            setting.clear_text();
+        }
+        ui.label(setting.get_unit());
+        if let Some(error) = setting.get_error() {
+            ui.colored_label(Color32::LIGHT_RED, error);
+        }
+    });
+}
+
 pub struct TimeGrapherUi {
     process_error: extras::NewError,
-    host: Connector,
+    hosts: Vec<Connector>,
+    host: usize,
     device: String,
     device_list: Vec<String>,
+    // supported configs of the selected host and device
+    input_configs: Option<(usize, String, Vec<InputConfig>)>,
+    audio_source: AudioSource,
+    channel: Channel,
+    wav_path: String,
     audio_taskhanle: Option<JoinHandle<()>>,
+    // analyzer settings of the running executor
+    settings_tx: Option<watch::Sender<AnalyzerSettings>>,
+    // noise learning of the running executor, the device it runs on and the learned profiles by device
+    learn_tx: Option<std_mpsc::Sender<f64>>,
+    learned_noise: Arc<std::sync::Mutex<Option<NoiseProfile>>>,
+    noise_device: Option<String>,
+    noise_profiles: BTreeMap<String, NoiseProfile>,
+    // name and sample rate of the running stream
+    stream_info: Option<(String, f64)>,
+    dropped_samples: Option<Arc<AtomicU64>>,
+    record: bool,
+    recorder: Arc<std::sync::Mutex<Option<Recorder>>>,
     start_btn: bool,
     stop_btn: bool,
     clear_btn: bool,
     show_data_type: ShowData,
+    plot_mode: PlotMode,
     rawdata: Arc<Mutex<AudioTrack>>,
     data: Arc<Mutex<AudioTrack>>,
     last_data: AudioTrack,
+    measurement: Arc<Mutex<Measurement>>,
+    last_measurement: Measurement,
     audio_settings: extras::AudioSettings,
     plot_settings: extras::PlotSettings
 }
 
 impl TimeGrapherUi {
-    pub fn new(mut cons: Vec<Connector>) -> Self {
-        let host = cons.remove(0);
-        let devices = host
-            .list_device_names()
-            .unwrap_or(vec!["Devices not found!".to_string()]);
-
-        Self {
+    pub fn new(cons: Vec<Connector>) -> Self {
+        let mut ui = Self {
             process_error: extras::NewError::default(),
-            host: host,
-            device: devices[0].clone(),
-            device_list: devices,
+            hosts: cons,
+            host: 0,
+            device: String::new(),
+            device_list: Vec::new(),
+            input_configs: None,
+            audio_source: AudioSource::Device,
+            channel: Channel::Mix,
+            wav_path: String::new(),
             audio_taskhanle: None,
+            settings_tx: None,
+            learn_tx: None,
+            learned_noise: Arc::new(std::sync::Mutex::new(None)),
+            noise_device: None,
+            noise_profiles: BTreeMap::new(),
+            stream_info: None,
+            dropped_samples: None,
+            record: false,
+            recorder: Arc::new(std::sync::Mutex::new(None)),
             start_btn: true,
             stop_btn: false,
             clear_btn: true,
             show_data_type: ShowData::Processed,
+            plot_mode: PlotMode::Trace,
             rawdata: Arc::new(Mutex::new(AudioTrack::new())),
             data: Arc::new(Mutex::new(AudioTrack::new())),
             last_data: AudioTrack::new(),
+            measurement: Arc::new(Mutex::new(Measurement::default())),
+            last_measurement: Measurement::default(),
             audio_settings: extras::AudioSettings::default(),
             plot_settings: extras::PlotSettings::default(),
+        };
+        ui.select_host(0);
+
+        match config::load_state() {
+            Ok(Some(state)) => ui.restore(state),
+            Ok(None) => {}
+            Err(e) => warn!("Settings not restored: {:}", e),
+        }
+        ui
+    }
+
+    // apply the settings and selections of the last session, hosts and devices
+    // that are gone keep the defaults
+    fn restore(&mut self, state: SavedState) {
+        self.audio_settings.restore(&state.audio);
+        self.plot_settings.restore(&state.plot);
+        self.noise_profiles = state.noise_profiles;
+        if let Some(channel) = state.channel {
+            self.channel = channel;
+        }
+        if let Some(index) = state.host.and_then(|name| self.hosts.iter().position(|host| host.name() == name)) {
+            self.select_host(index);
+        }
+        if let Some(device) = state.device.filter(|device| self.device_list.contains(device)) {
+            self.device = device;
+        }
+    }
+
+    fn saved_state(&self) -> SavedState {
+        SavedState {
+            host: self.hosts.get(self.host).map(|host| host.name()),
+            device: Some(self.device.clone()),
+            channel: Some(self.channel),
+            audio: self.audio_settings.clone(),
+            plot: self.plot_settings.clone(),
+            noise_profiles: self.noise_profiles.clone(),
+        }
+    }
+
+    // settings of the window together with the noise profile of the running device
+    fn analyzer_settings(&self) -> Result<AnalyzerSettings> {
+        let mut settings = self.audio_settings.analyzer_settings()?;
+        settings.noise_profile = self
+            .noise_device
+            .as_ref()
+            .and_then(|device| self.noise_profiles.get(device))
+            .cloned();
+        Ok(settings)
+    }
+
+    // the presence gate needs the speex denoiser on the device the next start runs on
+    fn presence_available(&self) -> bool {
+        let Ok(mut settings) = self.audio_settings.analyzer_settings() else {
+            return true;
+        };
+        if self.audio_source == AudioSource::Device {
+            settings.noise_profile = self.noise_profiles.get(&self.device).cloned();
+        }
+        settings.presence_available()
+    }
+
+    // forget the executor channels once the stream is gone
+    fn stream_stopped(&mut self) {
+        self.audio_taskhanle = None;
+        self.settings_tx = None;
+        self.learn_tx = None;
+        self.noise_device = None;
+        self.stream_info = None;
+    }
+
+    // rebuild the device list from the chosen host, keeping the device if it is still there
+    fn select_host(&mut self, index: usize) {
+        self.host = index;
+        self.device_list = self
+            .hosts
+            .get(index)
+            .and_then(|host| host.list_device_names())
+            .unwrap_or(vec!["Devices not found!".to_string()]);
+        self.device_list.sort();
+
+        if !self.device_list.contains(&self.device) {
+            self.device = self.device_list[0].clone();
+        }
+    }
+
+    fn host_name(&self) -> String {
+        self.hosts
+            .get(self.host)
+            .map(|host| host.name())
+            .unwrap_or("No host".to_string())
+    }
+
+    // queried once per device, opening a device to list its configs can be slow
+    fn input_configs(&mut self) -> Vec<InputConfig> {
+        let current = matches!(&self.input_configs, Some((host, device, _)) if *host == self.host && *device == self.device);
+        if !current {
+            let configs = match self.hosts.get(self.host) {
+                Some(host) => host.list_input_configs(&self.device).unwrap_or_else(|e| {
+                    warn!("{:}", e);
+                    Vec::new()
+                }),
+                None => Vec::new(),
+            };
+            self.input_configs = Some((self.host, self.device.clone(), configs));
+        }
+        self.input_configs
+            .as_ref()
+            .map(|(_, _, configs)| configs.clone())
+            .unwrap_or_default()
+    }
+
+    // query the hosts again so hot-plugged devices show up
+    fn refresh_devices(&mut self) {
+        let host_name = self.host_name();
+        match audioio::get_connectors() {
+            Ok(cons) => {
+                info!("Found Connectors {:?}", &cons);
+                self.hosts = cons;
+                self.input_configs = None;
+                let index = self
+                    .hosts
+                    .iter()
+                    .position(|host| host.name() == host_name)
+                    .unwrap_or(0);
+                self.select_host(index);
+            }
+            Err(e) => {
+                error!("Error while refreshing devices: {:}", e);
+                self.process_error.rais(format!("Error while refreshing devices: {:}", e));
+            }
+        }
+    }
+
+    fn start_recording(&mut self, source: String, samplerate: f64) {
+        let start_time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let meta = extras::SessionMeta {
+            device: source,
+            channel: self.channel,
+            samplerate,
+            settings: self.audio_settings.clone(),
+            start_time,
+        };
+        let path = format!("timegrapher_{:}.wav", start_time);
+
+        match Recorder::new(&path, samplerate, &meta) {
+            Ok(recorder) => {
+                info!("Recording to {:}", &path);
+                if let Ok(mut current) = self.recorder.lock() {
+                    *current = Some(recorder);
+                }
+            }
+            Err(e) => {
+                error!("Error while starting recording: {:}", e);
+                self.process_error.rais(format!("Error while starting recording: {:}", e));
+                self.record = false;
+            }
+        }
+    }
+
+    fn stop_recording(&mut self) {
+        let recorder = self.recorder.lock().ok().and_then(|mut current| current.take());
+        if let Some(recorder) = recorder {
+            let path = recorder.path().display().to_string();
+            match recorder.finalize() {
+                Ok(_) => info!("Recording saved to {:}", path),
+                Err(e) => {
+                    error!("Error while saving recording: {:}", e);
+                    self.process_error.rais(format!("Error while saving recording: {:}", e));
+                }
+            }
+        }
+    }
+
+    // open or close the recorder so that it follows the record toggle while sampling
+    fn sync_recorder(&mut self) {
+        let is_recording = self.recorder.lock().map(|r| r.is_some()).unwrap_or(false);
+        match (&self.stream_info, self.record, is_recording) {
+            (Some((source, samplerate)), true, false) => {
+                let (source, samplerate) = (source.clone(), *samplerate);
+                self.start_recording(source, samplerate);
+            }
+            (None, _, true) | (_, false, true) => self.stop_recording(),
+            _ => {}
         }
     }
 }
 
 impl App for TimeGrapherUi {
+    fn on_exit(&mut self, _gl: Option<&eframe::glow::Context>) {
+        match config::save_state(&self.saved_state()) {
+            Ok(path) => info!("Settings saved to {:}", path.display()),
+            Err(e) => error!("Unable to save settings: {:}", e),
+        }
+    }
+
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
         // Apply the updated style to the context and turn on dark mode
         ctx.set_style(Style {
@@ -69,6 +377,38 @@ impl App for TimeGrapherUi {
             ..Style::default()
         });
 
+        // finite sources stop the executor on their own
+        if self.audio_taskhanle.as_ref().is_some_and(|task| task.is_finished()) {
+            info!("Audio stream finished");
+            self.stream_stopped();
+            self.stop_btn = false;
+            self.start_btn = true;
+            self.clear_btn = true;
+        }
+
+        // status bar
+        egui::TopBottomPanel::bottom("Status").show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                match &self.stream_info {
+                    Some((source, samplerate)) => ui.label(format!("{:} @ {:.0} Hz", source, samplerate)),
+                    None => ui.label("Stopped"),
+                };
+                ui.separator();
+                let dropped = self
+                    .dropped_samples
+                    .as_ref()
+                    .map(|d| d.load(Ordering::Relaxed))
+                    .unwrap_or(0);
+                ui.label(format!("Dropped samples: {:}", dropped));
+                ui.separator();
+                ui.label(format!("Gaps in last frame: {:}", self.last_measurement.gaps));
+                if let Some(presence) = self.last_measurement.presence {
+                    ui.separator();
+                    ui.label(format!("Presence: {:.0} %", 100.0 * presence));
+                }
+            });
+        });
+
         egui::CentralPanel::default().show(ctx, |ui| {
             // Get the total available width for the UI
             let available_width = ui.available_width();
@@ -84,16 +424,72 @@ impl App for TimeGrapherUi {
                     Layout::left_to_right(Align::Min),
                     |ui| {
                         ui.vertical(|ui| {
-                            ui.label("select audio device: ");
-                            ComboBox::new("Audio device:", "")
-                                .selected_text(&self.device)
+                            ui.horizontal(|ui| {
+                                ui.radio_value(&mut self.audio_source, AudioSource::Device, "Device");
+                                ui.radio_value(&mut self.audio_source, AudioSource::File, "WAV file");
+                            });
+                            match self.audio_source {
+                                AudioSource::Device => {
+                                    ui.label("select audio host: ");
+                                    let mut host = self.host;
+                                    ComboBox::new("Audio host:", "")
+                                        .selected_text(self.host_name())
+                                        .show_ui(ui, |ui| {
+                                            self.hosts.iter().enumerate().for_each(|(index, con)| {
+                                                ui.selectable_value(&mut host, index, con.name());
+                                            });
+                                        });
+                                    if host != self.host {
+                                        self.select_host(host);
+                                    }
+
+                                    ui.label("select audio device: ");
+                                    ComboBox::new("Audio device:", "")
+                                        .selected_text(&self.device)
+                                        .show_ui(ui, |ui| {
+                                            let _ = &self.device_list.iter().for_each(|dev| {
+                                                ui.selectable_value(&mut self.device, dev.clone(), dev);
+                                            });
+                                        });
+                                    if ui
+                                        .add_enabled(self.start_btn, egui::Button::new("Refresh devices"))
+                                        .clicked()
+                                    {
+                                        self.refresh_devices();
+                                    }
+                                }
+                                AudioSource::File => {
+                                    ui.label("wav file path: ");
+                                    ui.add(
+                                        egui::TextEdit::singleline(&mut self.wav_path)
+                                            .hint_text("recording.wav"),
+                                    );
+                                }
+                            }
+
+                            ui.label("select channel: ");
+                            ComboBox::new("Channel:", "")
+                                .selected_text(self.channel.to_string())
                                 .show_ui(ui, |ui| {
-                                    let _ = &self.device_list.iter().for_each(|dev| {
-                                        ui.selectable_value(&mut self.device, dev.clone(), dev);
+                                    Channel::ALL.iter().for_each(|&channel| {
+                                        ui.selectable_value(&mut self.channel, channel, channel.to_string());
                                     });
                                 });
 
-                            ui.add_space(50.);
+                            ui.add_space(10.);
+
+                            ui.label("select beat rate: ");
+                            let bph = self.audio_settings.bph.get_value_mut();
+                            ComboBox::new("Beat rate:", "")
+                                .selected_text(if *bph == 0 { "Auto".to_string() } else { format!("{:} bph", bph) })
+                                .show_ui(ui, |ui| {
+                                    ui.selectable_value(bph, 0, "Auto");
+                                    STANDARD_BPH.iter().for_each(|&rate| {
+                                        ui.selectable_value(bph, rate, format!("{:} bph", rate));
+                                    });
+                                });
+
+                            ui.add_space(40.);
 
                             ui.horizontal(|ui| {
                                 if ui
@@ -110,37 +506,67 @@ impl App for TimeGrapherUi {
                                     // start process if not present
                                     if self.audio_taskhanle.is_none() {
                                         // here goes the code that creates stream and every
-                                        info!(
-                                            "Creating audio stream on device {:}:{:}",
-                                            &self.host, &self.device
-                                        );
-
-                                        match AudioStreamBuilder::new(&self.host, &self.device) {
-                                            Ok(streambuilder) => {
-                                                match streambuilder.build() {
-                                                    Ok(audiostream) => {
-                                                        // executor
-                                                        self.audio_taskhanle = spawn_executor(audiostream,
-                                                            ExecutorCTL{
-                                                                rawdata: Arc::clone(&self.rawdata),
-                                                                data: Arc::clone(&self.data),
-                                                                duration: self.audio_settings.sample_size.get_value().clone(),
-                                                                use_denoiser: if *self.audio_settings.use_denoiser.get_value() { 1.into() } else { 0.into() },
-                                                                noise_supr_level: self.audio_settings.noise_supr_level.get_value().clone(),
-                                                                use_agc: if *self.audio_settings.use_agc.get_value() { 1.into() } else { 0.into() },
-                                                                agc_level: self.audio_settings.agc_level.get_value().clone(),
-                                                                cutoff: self.audio_settings.cutoff.get_value().to_owned(),
-                                                            }
-                                                        );      
-                                                    }
-                                                    Err(e) => {
-                                                        error!("Error While building stream: {:}", e);
-                                                        self.process_error.rais(format!(
-                                                            "Error While building stream: {:}",
-                                                            e
-                                                        ));
+                                        let source = match self.audio_source {
+                                            AudioSource::Device => self.device.clone(),
+                                            AudioSource::File => self.wav_path.clone(),
+                                        };
+                                        let audiostream = match self.audio_source {
+                                            AudioSource::Device => {
+                                                info!(
+                                                    "Creating audio stream on device {:}:{:}",
+                                                    self.host_name(), &self.device
+                                                );
+                                                let transport = if *self.audio_settings.use_ring_buffer.get_value() {
+                                                    Transport::RingBuffer
+                                                } else {
+                                                    Transport::Channel
+                                                };
+                                                let (samplerate, sample_format, buffer_size) = self.audio_settings.stream_format();
+                                                self.hosts
+                                                    .get(self.host)
+                                                    .ok_or(anyhow!("No audio host available"))
+                                                    .and_then(|host| AudioStreamBuilder::new(host, &self.device))
+                                                    .and_then(|streambuilder| streambuilder.with_format(samplerate, sample_format))
+                                                    .and_then(|streambuilder| {
+                                                        streambuilder
+                                                            .with_buffer_size(buffer_size)
+                                                            .with_channel(self.channel)
+                                                            .with_transport(transport)
+                                                            .build()
+                                                    })
+                                            }
+                                            AudioSource::File => {
+                                                info!("Creating audio stream from file {:}", &self.wav_path);
+                                                WavStreamBuilder::new(&self.wav_path)
+                                                    .and_then(|streambuilder| streambuilder.with_channel(self.channel).build())
+                                            }
+                                        };
+
+                                        self.noise_device = match self.audio_source {
+                                            AudioSource::Device => Some(self.device.clone()),
+                                            AudioSource::File => None,
+                                        };
+                                        match self.analyzer_settings().and_then(|settings| Ok((settings, audiostream?))) {
+                                            Ok((settings, audiostream)) => {
+                                                self.stream_info = Some((source, audiostream.samplerate()));
+                                                self.dropped_samples = Some(audiostream.drop_counter());
+                                                // executor
+                                                let (settings_tx, settings_rx) = watch::channel(settings);
+                                                self.settings_tx = Some(settings_tx);
+                                                let (learn_tx, learn_rx) = std_mpsc::channel();
+                                                self.learn_tx = Some(learn_tx);
+                                                self.audio_taskhanle = spawn_executor(audiostream,
+                                                    ExecutorCTL{
+                                                        rawdata: Arc::clone(&self.rawdata),
+                                                        data: Arc::clone(&self.data),
+                                                        measurement: Arc::clone(&self.measurement),
+                                                        recorder: Arc::clone(&self.recorder),
+                                                        duration: self.audio_settings.block_size.get_value().clone(),
+                                                        settings: settings_rx,
+                                                        learn_noise: learn_rx,
+                                                        noise_profile: Arc::clone(&self.learned_noise),
                                                     }
-                                                }
+                                                );
                                             }
                                             Err(e) => {
                                                 // rais error
@@ -163,7 +589,7 @@ impl App for TimeGrapherUi {
                                     if let Some(task) = &self.audio_taskhanle {
                                         info!("Dropping stream");
                                         task.abort();
-                                        self.audio_taskhanle = None;
+                                        self.stream_stopped();
                                     }
                                 }
                             });
@@ -175,6 +601,7 @@ impl App for TimeGrapherUi {
                                 {
                                     self.rawdata = Arc::new(Mutex::new(AudioTrack::new()));
                                     self.data = Arc::new(Mutex::new(AudioTrack::new()));
+                                    self.measurement = Arc::new(Mutex::new(Measurement::default()));
                                 }
                                 if ui.add(egui::Button::new("Audio Settings")).clicked() {
                                     self.audio_settings.open();
@@ -183,6 +610,55 @@ impl App for TimeGrapherUi {
                                     self.plot_settings.open();
                                 }
                             });
+
+                            // noise is learned on the running device with the watch off the microphone
+                            ui.horizontal(|ui| {
+                                let learning = self.last_measurement.learning_noise && self.learn_tx.is_some();
+                                let can_learn = self.learn_tx.is_some() && self.noise_device.is_some() && !learning;
+                                if ui.add_enabled(can_learn, egui::Button::new("Learn noise")).clicked() {
+                                    if let Some(learn_tx) = &self.learn_tx {
+                                        let _ = learn_tx.send(NOISE_LEARN_TIME);
+                                    }
+                                }
+                                let has_profile = self.noise_profiles.contains_key(&self.device);
+                                if ui.add_enabled(has_profile && !learning, egui::Button::new("Forget noise")).clicked() {
+                                    self.noise_profiles.remove(&self.device);
+                                }
+                                if learning {
+                                    ui.label("Learning noise, keep the watch off the microphone");
+                                } else if has_profile {
+                                    ui.label("Noise profile learned");
+                                }
+                            });
+
+                            ui.checkbox(&mut self.record, "Record");
+
+                            ui.add_space(20.);
+
+                            // measurement readout
+                            if let Ok(measurement) = self.measurement.try_lock() {
+                                self.last_measurement = measurement.to_owned();
+                            }
+                            let rate_text = match self.last_measurement.rate {
+                                Some(rate) => format!("{:+.1} s/d", rate),
+                                None => "--".to_string(),
+                            };
+                            let bph_text = match self.last_measurement.bph {
+                                Some(bph) => format!("{:} bph", bph),
+                                None => "--".to_string(),
+                            };
+                            ui.heading(format!("Beat rate: {:}", bph_text));
+                            ui.heading(format!("Rate: {:}", rate_text));
+                            let beat_error_text = match (self.last_measurement.beat_error, self.last_measurement.beat_error_std) {
+                                (Some(be), Some(std)) => format!("{:.1} ms (±{:.1})", be, std),
+                                _ => "--".to_string(),
+                            };
+                            ui.heading(format!("Beat error: {:}", beat_error_text));
+                            let amplitude_text = match self.last_measurement.amplitude {
+                                Some(amplitude) => format!("{:.0}°", amplitude),
+                                None => "--".to_string(),
+                            };
+                            ui.heading(format!("Amplitude: {:}", amplitude_text));
                         });
                     },
                 );
@@ -248,17 +724,72 @@ impl App for TimeGrapherUi {
 
                             ui.add_space(20.);
 
-                            // transforme data into line
-                            let data = self.last_data.to_owned();
-                            let points: PlotPoints =
-                                data.track.iter().map(|&(t, v)| [t, v]).collect();
-                            let line = Line::new(points);
-                            Plot::new("Timegrapher")
-                                .view_aspect(3.0)
-                                .show(ui, |plot_ui| {
-                                    plot_ui.set_auto_bounds(Vec2b::new(true, true));
-                                    plot_ui.line(line)
-                                });
+                            match self.plot_mode {
+                                PlotMode::Waveform => {
+                                    // transforme data into line
+                                    let data = self.last_data.to_owned();
+                                    let points: PlotPoints =
+                                        data.track.iter().map(|&(t, v)| [t, v]).collect();
+                                    let line = Line::new(points);
+                                    Plot::new("Timegrapher")
+                                        .view_aspect(3.0)
+                                        .show(ui, |plot_ui| {
+                                            plot_ui.set_auto_bounds(Vec2b::new(true, true));
+                                            plot_ui.line(line)
+                                        });
+                                }
+                                PlotMode::Trace => {
+                                    // beats as dots, the y range covers one beat period
+                                    let bph = self.last_measurement.bph.unwrap_or(0);
+                                    let (ticks, tocks) = trace_points(&self.last_measurement.trace, bph);
+                                    let half_period = if bph == 0 { 1.0 } else { 500.0 * beat_period(bph) };
+                                    let trace = &self.last_measurement.trace;
+                                    let x_min = trace.first().map_or(0.0, |beat| beat.index as f64);
+                                    let x_max = trace.last().map_or(0.0, |beat| beat.index as f64);
+                                    let bounds = PlotBounds::from_min_max([x_min, -half_period], [x_max.max(x_min + 1.0), half_period]);
+                                    Plot::new("Timegrapher trace")
+                                        .view_aspect(3.0)
+                                        .x_axis_label("beat")
+                                        .y_axis_label("offset [ms]")
+                                        .show(ui, |plot_ui| {
+                                            plot_ui.set_plot_bounds(bounds);
+                                            plot_ui.points(Points::new(ticks).radius(1.5).color(Color32::LIGHT_BLUE).name("tick"));
+                                            plot_ui.points(Points::new(tocks).radius(1.5).color(Color32::LIGHT_RED).name("tock"));
+                                        });
+                                }
+                                PlotMode::Spectrum => {
+                                    // the noise floor the denoiser estimated against the spectrum of the last frame
+                                    let spectrum = self.last_measurement.spectrum.clone().unwrap_or_default();
+                                    let power = Line::new(PlotPoints::new(spectrum_points(&spectrum, &spectrum.power)))
+                                        .color(Color32::LIGHT_BLUE)
+                                        .name("signal");
+                                    let noise = Line::new(PlotPoints::new(spectrum_points(&spectrum, &spectrum.noise)))
+                                        .color(Color32::LIGHT_RED)
+                                        .name("noise estimate");
+                                    Plot::new("Spectrum")
+                                        .view_aspect(3.0)
+                                        .x_axis_label("frequency [Hz]")
+                                        .y_axis_label("power [dB]")
+                                        .legend(Legend::default())
+                                        .show(ui, |plot_ui| {
+                                            plot_ui.set_auto_bounds(Vec2b::new(true, true));
+                                            plot_ui.line(power);
+                                            plot_ui.line(noise);
+                                        });
+                                    if self.last_measurement.spectrum.is_none() {
+                                        ui.weak("The spectrum needs the denoise stage in the chain.");
+                                    }
+                                }
+                            }
+
+                            ui.horizontal(|ui| {
+                                ui.add_space(10.0);
+                                ui.radio_value(&mut self.plot_mode, PlotMode::Waveform, "Waveform");
+                                ui.add_space(10.0);
+                                ui.radio_value(&mut self.plot_mode, PlotMode::Trace, "Trace");
+                                ui.add_space(10.0);
+                                ui.radio_value(&mut self.plot_mode, PlotMode::Spectrum, "Spectrum");
+                            });
 
                             ui.add_space(20.);
                         });
@@ -267,6 +798,8 @@ impl App for TimeGrapherUi {
             });
         });
 
+        self.sync_recorder();
+
         // // Dialogues
         // Error
         let message = self.process_error.get_message().to_owned();
@@ -281,87 +814,198 @@ impl App for TimeGrapherUi {
         }
 
         // Audio settings section
-        let mut samplen_text = format!("{:.2}", self.audio_settings.sample_size.get_value());
-        let mut use_denoiser  = self.audio_settings.use_denoiser.get_value().clone();
-        let mut noise_supr_level_text  = format!("{:}", self.audio_settings.noise_supr_level.get_value());
-        let mut use_agc = self.audio_settings.use_agc.get_value().clone();
-        let mut agc_level_text = format!("{:}", self.audio_settings.agc_level.get_value());
-        let mut cutoff_text = format!("{:.2}", self.audio_settings.cutoff.get_value());
-        let mut is_open = self.audio_settings.is_open_mut();
+        let input_configs = if *self.audio_settings.is_open() { self.input_configs() } else { Vec::new() };
+        let mut samplerates: Vec<u32> = audioio::COMMON_SAMPLERATES
+            .iter()
+            .copied()
+            .chain(input_configs.iter().flat_map(|c| [c.min_samplerate, c.max_samplerate]))
+            .filter(|&rate| rate <= audioio::MAX_SAMPLERATE && input_configs.iter().any(|c| c.supports(rate)))
+            .collect();
+        samplerates.sort();
+        samplerates.dedup();
+        let mut sample_formats: Vec<String> = input_configs.iter().map(|c| c.sample_format.to_string()).collect();
+        sample_formats.sort();
+        sample_formats.dedup();
+        let buffer_hint = match input_configs.iter().filter_map(|c| c.buffer_size).reduce(|a, b| (a.0.min(b.0), a.1.max(b.1))) {
+            Some((min, max)) => format!("{:}..{:}, 0 for default", min, max),
+            None => "0 for default".to_string(),
+        };
+        let mut is_open = *self.audio_settings.is_open();
+        let presence_available = self.presence_available();
+        let settings = &mut self.audio_settings;
 
         egui::Window::new("Audio Settings")
             .open(&mut is_open)
             .show(ctx, |ui| {
-                ui.columns(2, |clo_ui| {
-                    clo_ui[0].vertical(|ui| {
-                        ui.label("Sample duration:");
-                        ui.add_space(3.0);
+                egui::Grid::new("Audio settings grid")
+                    .num_columns(2)
+                    .spacing([10.0, 4.0])
+                    .show(ui, |ui| {
+                        ui.label("Analysis window:");
+                        setting_field(ui, &mut settings.sample_size, "Analysis window", |v| format!("{:.2}", v));
+                        ui.end_row();
+
+                        ui.label("Block duration:");
+                        setting_field(ui, &mut settings.block_size, "Block duration", |v| format!("{:.2}", v));
+                        ui.end_row();
+
+                        ui.label("Sample rate:");
+                        let samplerate = settings.samplerate.get_value_mut();
+                        ComboBox::new("Sample rate:", "")
+                            .selected_text(if *samplerate == 0 { "Default".to_string() } else { format!("{:} Hz", samplerate) })
+                            .show_ui(ui, |ui| {
+                                ui.selectable_value(samplerate, 0, "Default");
+                                samplerates.iter().for_each(|&rate| {
+                                    ui.selectable_value(samplerate, rate, format!("{:} Hz", rate));
+                                });
+                            });
+                        ui.end_row();
+
+                        ui.label("Sample format:");
+                        let sample_format = settings.sample_format.get_value_mut();
+                        ComboBox::new("Sample format:", "")
+                            .selected_text(if sample_format.is_empty() { "Default".to_string() } else { sample_format.clone() })
+                            .show_ui(ui, |ui| {
+                                ui.selectable_value(sample_format, String::new(), "Default");
+                                sample_formats.iter().for_each(|format| {
+                                    ui.selectable_value(sample_format, format.clone(), format);
+                                });
+                            });
+                        ui.end_row();
+
+                        ui.label("Buffer size:");
+                        setting_field(ui, &mut settings.buffer_size, &buffer_hint, |v| format!("{:}", v));
+                        ui.end_row();
+
+                        ui.label("Lock-free ring buffer:");
+                        ui.checkbox(settings.use_ring_buffer.get_value_mut(), "");
+                        ui.end_row();
+
+                        ui.label("Filter:");
+                        let filter = settings.filter.get_value_mut();
+                        let label = extras::FILTERS
+                            .iter()
+                            .find(|(name, _)| name == filter)
+                            .map(|(_, label)| label.to_string())
+                            .unwrap_or(filter.clone());
+                        ComboBox::new("Filter:", "")
+                            .selected_text(label)
+                            .show_ui(ui, |ui| {
+                                extras::FILTERS.iter().for_each(|(name, label)| {
+                                    ui.selectable_value(filter, name.to_string(), *label);
+                                });
+                            });
+                        ui.end_row();
+
+                        let filter = settings.filter.get_value().clone();
+                        ui.label("Low corner:");
+                        ui.add_enabled_ui(filter == "highpass" || filter == "bandpass", |ui| {
+                            setting_field(ui, &mut settings.filter_low, "Low corner", |v| format!("{:.0}", v));
+                        });
+                        ui.end_row();
+
+                        ui.label("High corner:");
+                        ui.add_enabled_ui(filter == "lowpass" || filter == "bandpass", |ui| {
+                            setting_field(ui, &mut settings.filter_high, "High corner", |v| format!("{:.0}", v));
+                        });
+                        ui.end_row();
+
+                        if let Err(e) = settings.filter_stage() {
+                            ui.label("");
+                            ui.colored_label(Color32::LIGHT_RED, e.to_string());
+                            ui.end_row();
+                        }
+
                         ui.label("Use denoiser:");
-                        ui.add_space(3.0);
+                        ui.checkbox(settings.use_denoiser.get_value_mut(), "");
+                        ui.end_row();
+
                         ui.label("Noise suppression level");
-                        ui.add_space(3.0);
+                        setting_field(ui, &mut settings.noise_suppress, "Denoiser level", |v| format!("{:}", v));
+                        ui.end_row();
+
+                        ui.label("Presence gate");
+                        ui.add_enabled_ui(presence_available, |ui| {
+                            setting_field(ui, &mut settings.presence_gate, "0 is off", |v| format!("{:}", v));
+                        });
+                        ui.end_row();
+
+                        if !presence_available {
+                            ui.label("");
+                            ui.weak("Needs the speex denoise stage without a learned noise profile");
+                            ui.end_row();
+                        }
+
                         ui.label("Use Auto.Gain.Contr.");
-                        ui.add_space(3.0);
+                        ui.checkbox(settings.use_agc.get_value_mut(), "");
+                        ui.end_row();
+
                         ui.label("A.G.C. level");
-                        ui.add_space(3.0);
+                        setting_field(ui, &mut settings.agc_level, "AGC level", |v| format!("{:.0}", v));
+                        ui.end_row();
+
                         ui.label("Cutoff");
-                    });
+                        setting_field(ui, &mut settings.cutoff, "Use direct cutoff", |v| format!("{:.2}", v));
+                        ui.end_row();
 
-                    clo_ui[1].vertical(|ui| {
-                        ui.add(
-                            egui::TextEdit::singleline(&mut samplen_text)
-                                .hint_text("Sample duration")
-                                .desired_width(50.0),
-                        );
-                        ui.add(egui::Checkbox::new(&mut use_denoiser, ""));
-                        ui.add(
-                            egui::TextEdit::singleline(&mut noise_supr_level_text)
-                                .hint_text("Denoiser level in 100*dB")
-                                .desired_width(50.0),
-                        );
-                        ui.add(egui::Checkbox::new(&mut use_agc, ""));
-                        ui.add(
-                            egui::TextEdit::singleline(&mut agc_level_text)
-                                .hint_text("AGC level in 100*dB")
-                                .desired_width(50.0),
-                        );
-                        ui.add(
-                            egui::TextEdit::singleline(&mut cutoff_text)
-                                .hint_text("Use direct cutoff in dB")
-                                .desired_width(50.0),
-                        );
+                        ui.label("Lift angle");
+                        setting_field(ui, &mut settings.lift_angle, "Lift angle", |v| format!("{:.1}", v));
+                        ui.end_row();
                     });
-                });
-            });
 
-        self.audio_settings.sample_size.parse(samplen_text);
-        self.audio_settings.use_denoiser.update_value(use_denoiser);
-        self.audio_settings.noise_supr_level.parse(noise_supr_level_text);
-        self.audio_settings.use_agc.update_value(use_agc);
-        self.audio_settings.agc_level.parse(agc_level_text);
-        self.audio_settings.cutoff.parse(cutoff_text);
-    
+                ui.add_space(3.0);
+                ui.label("Processing chain:");
+                ui.add(
+                    egui::TextEdit::singleline(settings.chain.get_value_mut())
+                        .hint_text("denoise, envelope, cutoff")
+                        .desired_width(250.0),
+                );
+                if let Err(e) = Stage::parse_chain(settings.chain.get_value()) {
+                    ui.colored_label(Color32::LIGHT_RED, e.to_string());
+                }
+                ui.add_space(3.0);
+                ui.weak("Sample rate, format, buffer and block duration apply on the next start.");
+            });
+        *self.audio_settings.is_open_mut() = is_open;
 
         // Plot settings section
-        let mut ytext = format!("{:.2}", self.plot_settings.y_limit.get_value());
+        let mut is_open = *self.plot_settings.is_open();
+        let settings = &mut self.plot_settings;
         egui::Window::new("Plot Settings")
-            .open(&mut self.plot_settings.is_open_mut())
+            .open(&mut is_open)
             .show(ctx, |ui| {
-                ui.columns(2, |clo_ui| {
-                    clo_ui[0].vertical(|ui| {
+                egui::Grid::new("Plot settings grid")
+                    .num_columns(2)
+                    .spacing([10.0, 4.0])
+                    .show(ui, |ui| {
                         ui.label("Y limits:");
+                        setting_field(ui, &mut settings.y_limit, "Simetric limit On Y axis", |v| format!("{:.2}", v));
+                        ui.end_row();
                     });
+            });
+        *self.plot_settings.is_open_mut() = is_open;
 
-                    clo_ui[1].vertical(|ui| {
-                        ui.add(
-                            egui::TextEdit::singleline(&mut ytext)
-                                .hint_text("Simetric limit On Y axis")
-                                .desired_width(50.0),
-                        );
-                    });
+        // keep a learned noise profile for the device it was learned on
+        let learned = self.learned_noise.lock().ok().and_then(|mut learned| learned.take());
+        if let (Some(profile), Some(device)) = (learned, &self.noise_device) {
+            info!("Noise profile learned for {:}", device);
+            self.noise_profiles.insert(device.clone(), profile);
+        }
+
+        // hand changed settings to the running executor, invalid ones wait until they are fixed
+        if let Some(settings_tx) = &self.settings_tx {
+            if let Ok(settings) = self.analyzer_settings() {
+                settings_tx.send_if_modified(|current| {
+                    if *current == settings {
+                        false
+                    } else {
+                        info!("Applying new analyzer settings");
+                        *current = settings;
+                        true
+                    }
                 });
-            });
-        self.plot_settings.y_limit.parse(ytext);
+            }
+        }
 
         // Trigger repaint at regular intervals to keep the plot updating
         ctx.request_repaint();
@@ -0,0 +1,59 @@
+use crate::audio::io::Channel;
+use crate::signal::spectral::NoiseProfile;
+use crate::ui::extras::{AudioSettings, PlotSettings};
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, env, fs, path::PathBuf};
+
+// everything restored on the next launch, plain values come first as toml
+// needs them before the tables
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SavedState {
+    pub host: Option<String>,
+    pub device: Option<String>,
+    pub channel: Option<Channel>,
+    pub audio: AudioSettings,
+    pub plot: PlotSettings,
+    // learned noise by device name
+    pub noise_profiles: BTreeMap<String, NoiseProfile>,
+}
+
+// per user config directory following the platform conventions
+fn config_dir() -> Option<PathBuf> {
+    if cfg!(target_os = "windows") {
+        env::var_os("APPDATA").map(PathBuf::from)
+    } else if cfg!(target_os = "macos") {
+        env::var_os("HOME").map(|home| PathBuf::from(home).join("Library/Application Support"))
+    } else {
+        env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .filter(|dir| dir.is_absolute())
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+    }
+}
+
+pub fn config_path() -> Option<PathBuf> {
+    config_dir().map(|dir| dir.join("timegrapher").join("settings.toml"))
+}
+
+// None on the first launch
+pub fn load_state() -> Result<Option<SavedState>> {
+    let path = config_path().ok_or(anyhow!("Unable to locate the config directory"))?;
+    if !path.exists() {
+        return Ok(None);
+    }
+    let text = fs::read_to_string(&path).with_context(|| format!("Unable to read {:}", path.display()))?;
+    let state = basic_toml::from_str(&text).with_context(|| format!("Unable to parse {:}", path.display()))?;
+    Ok(Some(state))
+}
+
+pub fn save_state(state: &SavedState) -> Result<PathBuf> {
+    let path = config_path().ok_or(anyhow!("Unable to locate the config directory"))?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("Unable to create {:}", dir.display()))?;
+    }
+    let text = basic_toml::to_string(state)?;
+    fs::write(&path, text).with_context(|| format!("Unable to write {:}", path.display()))?;
+    Ok(path)
+}
@@ -1,5 +1,6 @@
+use serde::{Deserialize, Serialize};
 use std::default::Default;
-use std::fmt::Debug;
+use std::fmt::{Debug, Display};
 use std::str::FromStr;
 
 // Define Trear of Parsible
@@ -9,6 +10,12 @@ pub trait ParseFilter: FromStr + Default {
     fn filter(c: char) -> bool;
 }
 
+impl ParseFilter for u8 {
+    fn filter(c: char) -> bool {
+        c.to_ascii_lowercase().is_ascii_digit()
+    }
+}
+
 impl ParseFilter for u32 {
     fn filter(c: char) -> bool {
         c.to_ascii_lowercase().is_ascii_digit()
@@ -45,6 +52,12 @@ impl ParseFilter for f64 {
     }
 }
 
+impl ParseFilter for String {
+    fn filter(_c: char) -> bool {
+        true
+    }
+}
+
 impl ParseFilter for bool {
     fn filter(c: char) -> bool {
         c == '0' || c == '1'
@@ -55,41 +68,112 @@ impl ParseFilter for bool {
 // Define trait for individual Add Setting
 #[allow(dead_code)]
 pub trait AppSetting<T>: Default + Debug + Clone where
-T: ParseFilter + Debug + Clone,
+T: ParseFilter + Debug + Clone + PartialOrd + Display,
  {
     fn new(value: T) -> Self;
+    // inclusive bounds checked by parse and restore
+    fn with_range(self, min: T, max: T) -> Self;
+    fn with_unit(self, unit: &str) -> Self;
+    // invalid text is kept together with an error and leaves the value untouched
     fn parse(&mut self, str: String);
     fn get_value(&self) -> &T;
     fn get_value_mut(&mut self) -> &mut T;
     fn update_value(&mut self, value: T);
+    fn get_min(&self) -> Option<&T>;
+    fn get_max(&self) -> Option<&T>;
+    fn get_unit(&self) -> &str;
+    fn get_error(&self) -> Option<&String>;
+    // text being edited, None once it was accepted and cleared
+    fn get_text(&self) -> Option<&String>;
+    fn clear_text(&mut self);
+
+    fn validate(&self, value: &T) -> Result<(), String> {
+        let unit = self.get_unit();
+        match (self.get_min(), self.get_max()) {
+            (Some(min), Some(max)) if value < min || value > max => {
+                Err(format!("Must be between {:} and {:} {:}", min, max, unit).trim_end().to_string())
+            }
+            (Some(min), None) if value < min => Err(format!("Must be at least {:} {:}", min, unit).trim_end().to_string()),
+            (None, Some(max)) if value > max => Err(format!("Must be at most {:} {:}", max, unit).trim_end().to_string()),
+            _ => Ok(()),
+        }
+    }
+
+    // take over a saved value if it is still valid
+    fn restore(&mut self, saved: &Self) {
+        if self.validate(saved.get_value()).is_ok() {
+            self.update_value(saved.get_value().clone());
+        }
+    }
+
+    // text for an edit field, the pending text or the value formatted by `format`
+    fn edit_text(&self, format: impl Fn(&T) -> String) -> String {
+        self.get_text().cloned().unwrap_or_else(|| format(self.get_value()))
+    }
 }
 
 // create structure of type Setting that implements AppSetting trait
-#[derive(Default, Debug, Clone)]
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(transparent)]
 pub struct Setting<T>
 where T: ParseFilter + Default
 {
-    value: T
+    value: T,
+    #[serde(skip)]
+    min: Option<T>,
+    #[serde(skip)]
+    max: Option<T>,
+    #[serde(skip)]
+    unit: String,
+    #[serde(skip)]
+    text: Option<String>,
+    #[serde(skip)]
+    error: Option<String>,
 }
 
 
 impl<T> AppSetting<T> for Setting<T>
-where T: ParseFilter + Debug + Clone,
+where T: ParseFilter + Debug + Clone + PartialOrd + Display,
 {
     fn new(value: T) -> Self{
         Self{
-            value
+            value,
+            min: None,
+            max: None,
+            unit: String::new(),
+            text: None,
+            error: None,
         }
     }
 
+    fn with_range(mut self, min: T, max: T) -> Self {
+        self.min = Some(min);
+        self.max = Some(max);
+        self
+    }
+
+    fn with_unit(mut self, unit: &str) -> Self {
+        self.unit = unit.to_string();
+        self
+    }
+
     fn parse(&mut self, str: String) {
-        let parsed_value = str.chars()
-            .filter(|c: &char| T::filter(*c))
-            .collect::<String>()
-            .parse::<T>()
-            .unwrap_or(T::default());
-        
-        self.value = parsed_value;
+        let parsed_value = if !str.chars().all(|c: char| T::filter(c)) {
+            Err("Contains invalid characters".to_string())
+        } else {
+            str.trim()
+                .parse::<T>()
+                .map_err(|_| format!("'{:}' is not a valid value", str))
+        };
+
+        match parsed_value.and_then(|value| self.validate(&value).map(|_| value)) {
+            Ok(value) => {
+                self.value = value;
+                self.error = None;
+            }
+            Err(error) => self.error = Some(error),
+        }
+        self.text = Some(str);
     }
 
     fn get_value(&self) -> &T {
@@ -102,6 +186,35 @@ where T: ParseFilter + Debug + Clone,
 
     fn update_value(&mut self, value: T) {
         self.value = value;
+        self.text = None;
+        self.error = None;
+    }
+
+    fn get_min(&self) -> Option<&T> {
+        self.min.as_ref()
+    }
+
+    fn get_max(&self) -> Option<&T> {
+        self.max.as_ref()
+    }
+
+    fn get_unit(&self) -> &str {
+        &self.unit
+    }
+
+    fn get_error(&self) -> Option<&String> {
+        self.error.as_ref()
+    }
+
+    fn get_text(&self) -> Option<&String> {
+        self.text.as_ref()
+    }
+
+    // invalid text stays until it is fixed
+    fn clear_text(&mut self) {
+        if self.error.is_none() {
+            self.text = None;
+        }
     }
 }
 
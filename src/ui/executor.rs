@@ -1,60 +1,138 @@
 use crate::audio::io::AudioStream;
+use crate::audio::record::Recorder;
 use crate::audio::track::AudioTrack;
-use crate::signal::{speexdsp, calculator};
-use std::sync::Arc;
-use tokio::{spawn, sync::Mutex, task::JoinHandle};
-use crate::signal::utils;
+use crate::signal::analyzer::{Analyzer, AnalyzerSettings};
+use crate::signal::measure::Measurement;
+use crate::signal::spectral::NoiseProfile;
+use anyhow::Result;
+use std::sync::{atomic::Ordering, mpsc as std_mpsc, Arc};
+use std::thread;
+use log::error;
+use tokio::{spawn, sync::{mpsc::{self, error::TrySendError}, watch, Mutex}, task::JoinHandle};
+
+// blocks waiting for the processing thread, a live source drops what does not fit
+const BLOCK_QUEUE: usize = 2;
 
 pub struct ExecutorCTL {
     pub rawdata: Arc<Mutex<AudioTrack>>,
     pub data: Arc<Mutex<AudioTrack>>,
+    pub measurement: Arc<Mutex<Measurement>>,
+    pub recorder: Arc<std::sync::Mutex<Option<Recorder>>>,
+    // length of the blocks read from the stream in seconds
     pub duration: f64,
-    pub use_denoiser: i32,
-    pub noise_supr_level: i32,
-    pub use_agc: i32, 
-    pub agc_level: i32,
-    pub cutoff: f64,
+    // changes are picked up before the next block
+    pub settings: watch::Receiver<AnalyzerSettings>,
+    // seconds of noise to learn from the next blocks and the profile once learned
+    pub learn_noise: std_mpsc::Receiver<f64>,
+    pub noise_profile: Arc<std::sync::Mutex<Option<NoiseProfile>>>,
+}
+
+// the analyzer with its Speex state lives on its own thread for the whole capture,
+// it ends once the executor drops the block sender or the result receiver
+fn spawn_processor(
+    mut settings: watch::Receiver<AnalyzerSettings>,
+    learn_noise: std_mpsc::Receiver<f64>,
+    noise_profile: Arc<std::sync::Mutex<Option<NoiseProfile>>>,
+    mut blocks: mpsc::Receiver<AudioTrack>,
+    results: mpsc::Sender<(AudioTrack, AudioTrack, Measurement)>,
+) -> Result<()> {
+    thread::Builder::new()
+        .name("signal-processing".to_string())
+        .spawn(move || {
+            let mut analyzer = Analyzer::new(settings.borrow_and_update().clone());
+            while let Some(block) = blocks.blocking_recv() {
+                if settings.has_changed().unwrap_or(false) {
+                    analyzer.update_settings(settings.borrow_and_update().clone());
+                }
+                if let Ok(seconds) = learn_noise.try_recv() {
+                    analyzer.learn_noise(seconds);
+                }
+                let (track, result) = analyzer.push(block);
+                if let Some(profile) = analyzer.take_learned_noise() {
+                    if let Ok(mut learned) = noise_profile.lock() {
+                        *learned = Some(profile);
+                    }
+                }
+                if results.blocking_send((analyzer.window(), track, result)).is_err() {
+                    break;
+                }
+            }
+        })?;
+    Ok(())
 }
 
 pub fn spawn_executor(aust: AudioStream, ctl: ExecutorCTL) -> Option<JoinHandle<()>> {
-    // calclulate framesize
+    // calclulate blocksize, the analyzer keeps the overlap with the previous blocks
     let sampling_rate = aust.samplerate();
-    let frame_size: f64 = ctl.duration * sampling_rate;
-    let frame_size: i64 = frame_size.round() as i64;
+    let block_size: f64 = ctl.duration * sampling_rate;
+    let block_size: i64 = (block_size.round() as i64).max(1);
 
-    let handle = spawn(async move {
+    let (block_tx, block_rx) = mpsc::channel(BLOCK_QUEUE);
+    let (result_tx, mut result_rx) = mpsc::channel(1);
+    let ExecutorCTL { rawdata, data, measurement, recorder, settings, learn_noise, noise_profile, .. } = ctl;
+    if let Err(e) = spawn_processor(settings, learn_noise, noise_profile, block_rx, result_tx) {
+        error!("Unable to start signal processing due to {:}", e);
+        return None;
+    }
+
+    // a live source keeps being read while the previous block is processed, finite
+    // sources (wav files, the generator) wait for room so that nothing is lost
+    let live = aust.is_live();
+    let dropped = aust.drop_counter();
+    let reader = async move {
         loop {
-            let mut track = aust.get_track_by_framesize(frame_size).await;
-    
-            let mut rawdata = ctl.rawdata.lock().await;
-            *rawdata = track.clone();
-
-            let frame: Vec<f32> = track.get_volume().iter().map(|&v| v as f32).collect();
-            let processed_frame = tokio::task::spawn_blocking(move || {
-                // Create the Denoiser and process the frame inside the blocking task
-                let speex = speexdsp::Denoiser::new(frame_size as i32, sampling_rate as i32)
-                    .set_ctl(speexdsp::SetControll::Denoise, ctl.use_denoiser)
-                    .set_ctl(speexdsp::SetControll::NoiseSuppress, ctl.noise_supr_level)
-                    .set_ctl(speexdsp::SetControll::Agc, ctl.use_agc)
-                    .set_ctl(speexdsp::SetControll::AgcLevel, ctl.agc_level);
-                
-                let mut frame = frame; // mutable frame
-                speex.process(&mut frame);
-                frame
-            })
-            .await
-            .unwrap();
-    
-            track.update_volume(processed_frame);
-
-            track = calculator::BitCalculator::new(track).run_calculator();
-
-            track = utils::cutt_off(&track, ctl.cutoff);
-
-            let mut data = ctl.data.lock().await;
+            let track = aust.get_track_by_framesize(block_size).await;
+
+            // finite sources end with a partial block
+            let finished = (track.track.len() as i64) < block_size;
+            if track.track.is_empty() {
+                break;
+            }
+
+            if let Ok(mut recorder) = recorder.lock() {
+                if let Some(recorder) = recorder.as_mut() {
+                    if let Err(e) = recorder.write_track(&track) {
+                        error!("Error while recording to {:}: {:}", recorder.path().display(), e);
+                    }
+                }
+            }
+
+            if live {
+                match block_tx.try_send(track) {
+                    Ok(()) => {}
+                    Err(TrySendError::Full(track)) => {
+                        dropped.fetch_add(track.track.len() as u64, Ordering::Relaxed);
+                    }
+                    Err(TrySendError::Closed(_)) => break,
+                }
+            } else if block_tx.send(track).await.is_err() {
+                break;
+            }
+            if finished {
+                break;
+            }
+        }
+        // the processing thread finishes the queued blocks once the sender is gone
+    };
+
+    let publisher = async move {
+        while let Some((window, track, result)) = result_rx.recv().await {
+            let mut rawdata = rawdata.lock().await;
+            *rawdata = window;
+
+            let mut data = data.lock().await;
             *data = track;
+
+            let mut measurement = measurement.lock().await;
+            *measurement = result;
         }
+    };
+
+    // both run in the one task, aborting it drops the channels and so also stops
+    // the processing thread after its current block
+    let handle = spawn(async move {
+        tokio::join!(reader, publisher);
     });
-    
+
     Some(handle)
 }
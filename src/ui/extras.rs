@@ -1,28 +1,142 @@
+use crate::audio::io::{sample_format_from_str, Channel};
+use cpal::SampleFormat;
+use crate::signal::analyzer::AnalyzerSettings;
+use crate::signal::processor::Stage;
+use anyhow::{anyhow, Result};
 use crate::ui::defs::*;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone)]
+// filter stages the settings window offers in front of the chain, by stage name and label
+pub const FILTERS: [(&str, &str); 4] = [
+    ("", "Off"),
+    ("highpass", "High-pass"),
+    ("lowpass", "Low-pass"),
+    ("bandpass", "Band-pass"),
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct AudioSettings {
+    #[serde(skip)]
     is_open: bool,
+    // analysis window and the block length it advances by, in seconds
     pub sample_size: Setting<f64>,
+    pub block_size: Setting<f64>,
+    // 0 and an empty format keep the device default
+    pub samplerate: Setting<u32>,
+    pub sample_format: Setting<String>,
+    // fixed buffer size in frames, 0 lets the host decide
+    pub buffer_size: Setting<u32>,
+    pub use_ring_buffer: Setting<bool>,
+    // one of FILTERS with its corner frequencies, the high-pass uses the low one
+    // and the low-pass the high one
+    pub filter: Setting<String>,
+    pub filter_low: Setting<f64>,
+    pub filter_high: Setting<f64>,
     pub use_denoiser: Setting<bool>,
-    pub noise_supr_level: Setting<i32>,
+    pub noise_suppress: Setting<i32>,
     pub use_agc: Setting<bool>,
-    pub agc_level: Setting<i32>,
+    pub agc_level: Setting<f64>,
+    // 0 publishes beats regardless of the denoiser's speech probability
+    pub presence_gate: Setting<u8>,
     pub cutoff: Setting<f64>,
+    // 0 selects automatic beat rate detection
+    pub bph: Setting<u32>,
+    pub lift_angle: Setting<f64>,
+    // comma separated processing stages, see signal::processor::Stage
+    pub chain: Setting<String>,
 }
 
 impl Default for AudioSettings {
     fn default() -> Self {
         Self {
             is_open: false,
-            sample_size: Setting::new(5.0),
+            sample_size: Setting::new(2.0).with_range(0.1, 60.0).with_unit("s"),
+            block_size: Setting::new(0.2).with_range(0.01, 10.0).with_unit("s"),
+            samplerate: Setting::new(0),
+            sample_format: Setting::new(String::new()),
+            buffer_size: Setting::new(0).with_range(0, 65536).with_unit("frames"),
+            use_ring_buffer: Setting::new(false),
+            filter: Setting::new(String::new()),
+            filter_low: Setting::new(1000.0).with_range(10.0, 100000.0).with_unit("Hz"),
+            filter_high: Setting::new(10000.0).with_range(10.0, 100000.0).with_unit("Hz"),
             use_denoiser: Setting::new(true),
-            noise_supr_level: Setting::new(8000),
+            noise_suppress: Setting::new(-30).with_range(-100, 0).with_unit("dB"),
             use_agc: Setting::new(true),
-            agc_level: Setting::new(16000),
-            cutoff: Setting::new(-60.0),
+            agc_level: Setting::new(8000.0).with_range(1.0, 32768.0),
+            presence_gate: Setting::new(0).with_range(0, 100).with_unit("%"),
+            cutoff: Setting::new(-60.0).with_range(-150.0, 0.0).with_unit("dB"),
+            bph: Setting::new(0),
+            lift_angle: Setting::new(52.0).with_range(10.0, 90.0).with_unit("°"),
+            chain: Setting::new(Stage::format_chain(&Stage::default_chain())),
+        }
+    }
+}
+
+impl AudioSettings {
+    // values of a saved session that are still within the ranges of the current build
+    pub fn restore(&mut self, saved: &AudioSettings) {
+        self.sample_size.restore(&saved.sample_size);
+        self.block_size.restore(&saved.block_size);
+        self.samplerate.restore(&saved.samplerate);
+        self.sample_format.restore(&saved.sample_format);
+        self.buffer_size.restore(&saved.buffer_size);
+        self.use_ring_buffer.restore(&saved.use_ring_buffer);
+        self.filter.restore(&saved.filter);
+        self.filter_low.restore(&saved.filter_low);
+        self.filter_high.restore(&saved.filter_high);
+        self.use_denoiser.restore(&saved.use_denoiser);
+        self.noise_suppress.restore(&saved.noise_suppress);
+        self.use_agc.restore(&saved.use_agc);
+        self.agc_level.restore(&saved.agc_level);
+        self.presence_gate.restore(&saved.presence_gate);
+        self.cutoff.restore(&saved.cutoff);
+        self.bph.restore(&saved.bph);
+        self.lift_angle.restore(&saved.lift_angle);
+        self.chain.restore(&saved.chain);
+    }
+
+    pub fn stream_format(&self) -> (Option<u32>, Option<SampleFormat>, Option<u32>) {
+        let samplerate = Some(*self.samplerate.get_value()).filter(|&rate| rate > 0);
+        let sample_format = sample_format_from_str(self.sample_format.get_value());
+        let buffer_size = Some(*self.buffer_size.get_value()).filter(|&size| size > 0);
+        (samplerate, sample_format, buffer_size)
+    }
+
+    // the filter runs first so that the streaming stages after it see the passband only
+    pub fn filter_stage(&self) -> Result<Option<Stage>> {
+        let (low, high) = (*self.filter_low.get_value(), *self.filter_high.get_value());
+        match self.filter.get_value().as_str() {
+            "" => Ok(None),
+            "highpass" => Ok(Some(Stage::HighPass(low))),
+            "lowpass" => Ok(Some(Stage::LowPass(high))),
+            "bandpass" if low < high => Ok(Some(Stage::BandPass(low, high))),
+            "bandpass" => Err(anyhow!("The low corner of the band-pass must be below the high one")),
+            filter => Err(anyhow!("Unknown filter '{:}'", filter)),
         }
     }
+
+    pub fn analyzer_settings(&self) -> Result<AnalyzerSettings> {
+        let chain = self
+            .filter_stage()?
+            .into_iter()
+            .chain(Stage::parse_chain(self.chain.get_value())?)
+            .collect();
+        Ok(AnalyzerSettings {
+            use_denoiser: *self.use_denoiser.get_value(),
+            noise_suppress: *self.noise_suppress.get_value(),
+            use_agc: *self.use_agc.get_value(),
+            agc_level: *self.agc_level.get_value(),
+            presence_gate: *self.presence_gate.get_value(),
+            cutoff: *self.cutoff.get_value(),
+            bph: *self.bph.get_value(),
+            lift_angle: *self.lift_angle.get_value(),
+            window: *self.sample_size.get_value(),
+            chain,
+            // noise profiles belong to a device, the app adds the one of the running device
+            noise_profile: None,
+        })
+    }
 }
 
 impl AppSettingCollection for AudioSettings {
@@ -35,8 +149,10 @@ impl AppSettingCollection for AudioSettings {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct PlotSettings {
+    #[serde(skip)]
     is_open: bool,
     pub y_limit: Setting<f64>,
 }
@@ -45,11 +161,17 @@ impl Default for PlotSettings {
     fn default() -> Self {
         Self {
             is_open: false,
-            y_limit: Setting::new(0.01),
+            y_limit: Setting::new(0.01).with_range(0.0001, 10.0),
         }
     }
 }
 
+impl PlotSettings {
+    pub fn restore(&mut self, saved: &PlotSettings) {
+        self.y_limit.restore(&saved.y_limit);
+    }
+}
+
 impl AppSettingCollection for PlotSettings {
     fn is_open(&self) -> &bool {
         &self.is_open
@@ -60,6 +182,17 @@ impl AppSettingCollection for PlotSettings {
     }
 }
 
+// written next to every recording
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionMeta {
+    pub device: String,
+    pub channel: Channel,
+    pub samplerate: f64,
+    pub settings: AudioSettings,
+    // unix time in seconds
+    pub start_time: u64,
+}
+
 #[derive(Debug, Clone)]
 pub struct NewError {
     is_error: bool,
@@ -1,4 +1,5 @@
 pub mod app;
 mod extras;
 mod defs;
-mod executor;
\ No newline at end of file
+mod executor;
+mod config;
\ No newline at end of file